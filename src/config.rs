@@ -1,7 +1,7 @@
 use crate::{
     OutputFormat, atom,
     git::HistMeta,
-    helpers::de_regex_set,
+    helpers::{de_regex_set, full_regex_set},
     output::{Page, write_redirect_page},
 };
 use regex::RegexSet;
@@ -22,6 +22,22 @@ pub struct ClamConfig {
     pub feed: Vec<FeedConfig>,
     #[serde(default)]
     pub redirect: Vec<RedirectConfig>,
+    /// org macros usable from every page
+    #[serde(default)]
+    pub macros: HashMap<String, String>,
+    /// org file with `#+MACRO:` definitions usable from every page
+    pub macro_file: Option<PathBuf>,
+    /// cum buckets gathered from every page, instead of just the one listing them
+    #[serde(default)]
+    pub site_buckets: Vec<String>,
+    /// pages the orphan check starts walking links from, besides `index.org`
+    #[cfg(feature = "util")]
+    #[serde(default)]
+    pub entry_points: Vec<PathBuf>,
+    /// pages that are fine being orphans
+    #[cfg(feature = "util")]
+    #[serde(default = "RegexSet::empty", deserialize_with = "de_regex_set")]
+    pub orphan_allow: RegexSet,
 }
 
 #[derive(Deserialize, Debug)]
@@ -47,10 +63,6 @@ const fn default_max_items() -> usize {
     42
 }
 
-fn full_regex_set() -> RegexSet {
-    RegexSet::new(["."]).unwrap()
-}
-
 #[derive(Deserialize, Debug)]
 pub struct RedirectConfig {
     pub path: PathBuf,
@@ -64,13 +76,9 @@ pub struct OverrideConfig {
     pub format: OutputFormat,
 }
 
-pub fn handle_config(
-    pages: &HashMap<PathBuf, Page>,
-    metadata: &HashMap<PathBuf, HistMeta>,
-    overrides: OverrideConfig,
-) -> Option<ClamConfig> {
-    let config = fs::read_to_string(".clam.toml").ok()?;
-    let mut config: ClamConfig = match toml_edit::de::from_str(&config) {
+/// parse the contents of a config file and apply overrides
+pub fn parse_config(config: &str, overrides: &OverrideConfig) -> Option<ClamConfig> {
+    let mut config: ClamConfig = match toml_edit::de::from_str(config) {
         Ok(c) => c,
         Err(e) => {
             eprintln!("could not parse config: {e}");
@@ -78,19 +86,42 @@ pub fn handle_config(
         }
     };
 
-    let id = config.id.unwrap_or_else(|| config.url.clone());
-    let url = overrides.url.unwrap_or(config.url);
+    if config.id.is_none() {
+        config.id = Some(config.url.clone());
+    }
+    if let Some(url) = &overrides.url {
+        config.url.clone_from(url);
+    }
+    config.inline = overrides.inline.unwrap_or(config.inline);
+
+    Some(config)
+}
 
-    if !config.feed.is_empty() {
-        let entries = atom::entries(pages, metadata).ok()?;
+/// read and parse `.clam.toml` from the current directory
+pub fn read_config(overrides: &OverrideConfig) -> Option<ClamConfig> {
+    let config = fs::read_to_string(".clam.toml").ok()?;
+    parse_config(&config, overrides)
+}
 
+/// write out feeds and redirects
+pub fn handle_config(
+    config: &ClamConfig,
+    pages: &HashMap<PathBuf, Page>,
+    metadata: &HashMap<PathBuf, HistMeta>,
+    format: OutputFormat,
+) {
+    let id = config.id.as_deref().unwrap_or(&config.url);
+
+    if !config.feed.is_empty()
+        && let Ok(entries) = atom::entries(pages, metadata)
+    {
         for feed in &config.feed {
             if let Err(e) = atom::write_feed(
                 feed,
-                &id,
-                &url,
+                id,
+                &config.url,
                 entries.as_slice(),
-                matches!(overrides.format, OutputFormat::Html),
+                matches!(format, OutputFormat::Html),
             ) {
                 eprintln!("skipping {}: {}", feed.path.display(), e);
             }
@@ -98,14 +129,8 @@ pub fn handle_config(
     }
 
     for RedirectConfig { path, target } in &config.redirect {
-        if let Err(e) = write_redirect_page(overrides.format, path, target) {
+        if let Err(e) = write_redirect_page(format, path, target) {
             eprintln!("skipping redirect {}: {}", path.display(), e);
         }
     }
-
-    config.id = Some(id);
-    config.url = url;
-    config.inline = overrides.inline.unwrap_or(config.inline);
-
-    Some(config)
 }
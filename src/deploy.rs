@@ -0,0 +1,179 @@
+use crate::{
+    Error,
+    config::{ClamConfig, OverrideConfig},
+    generate,
+};
+use git2::{Commit, Repository};
+use std::{
+    env::{current_dir, set_current_dir},
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// `out` split into its parent directory and name, so generations can go next to it
+fn split_out(out: &Path) -> Result<(PathBuf, OsString), Error> {
+    let out = std::path::absolute(out).map_err(Error::Dir)?;
+    match (out.parent(), out.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent.to_path_buf(), name.to_owned())),
+        _ => Err(Error::UnsafePath),
+    }
+}
+
+/// name of a sibling of `out`, like `.public.lock`
+fn sibling(parent: &Path, name: &OsString, suffix: &str) -> PathBuf {
+    let mut sibling = OsString::from(".");
+    sibling.push(name);
+    sibling.push(suffix);
+    parent.join(sibling)
+}
+
+/// whether a path is a build made by [`build_into`], as opposed to something else next to it
+/// that is not ours to delete
+fn is_generation(parent: &Path, name: &OsString, path: &Path) -> bool {
+    let prefix = sibling(parent, name, ".");
+    let (Some(prefix), Some(file_name)) = (prefix.file_name(), path.file_name()) else {
+        return false;
+    };
+    path.parent() == Some(parent)
+        && file_name
+            .as_encoded_bytes()
+            .strip_prefix(prefix.as_encoded_bytes())
+            .is_some_and(|rest| rest.first().is_some_and(u8::is_ascii_digit))
+}
+
+/// a fresh name for a generation, starting with the time so they sort from oldest to newest
+fn generation_name(parent: &Path, name: &OsString, tag: &str) -> Result<PathBuf, Error> {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(Error::Clock)?;
+    Ok(sibling(
+        parent,
+        name,
+        &format!(".{}.{:09}-{tag}", time.as_secs(), time.subsec_nanos()),
+    ))
+}
+
+/// remove all but the newest `keep` generations that are not in use
+fn prune(parent: &Path, name: &OsString, current: &Path, keep: usize) -> Result<(), Error> {
+    let mut old: Vec<PathBuf> = fs::read_dir(parent)
+        .map_err(Error::Dir)?
+        .filter_map(|e| Some(e.ok()?.path()))
+        .filter(|p| p != current && p.is_dir() && is_generation(parent, name, p))
+        .collect();
+    old.sort();
+    let remove = old.len().saturating_sub(keep);
+    for path in &old[..remove] {
+        fs::remove_dir_all(path).map_err(Error::Dir)?;
+    }
+    Ok(())
+}
+
+/// hold an exclusive lock next to `out` while running something, so builds do not interleave
+pub fn with_lock<T>(out: &Path, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    let (parent, name) = split_out(out)?;
+    let file = fs::File::create(sibling(&parent, &name, ".lock")).map_err(Error::Lock)?;
+    file.lock().map_err(Error::Lock)?;
+    let res = f();
+    _ = file.unlock();
+    res
+}
+
+/// run a build inside `dir`, going back to where we were afterwards
+fn build_in(
+    dir: &Path,
+    repo: &Repository,
+    commit: &Commit,
+    overrides: &OverrideConfig,
+    config: Option<&ClamConfig>,
+) -> Result<(), Error> {
+    let back = current_dir().map_err(Error::Dir)?;
+    set_current_dir(dir).map_err(Error::Dir)?;
+    let res = generate(repo, commit, overrides, config);
+    set_current_dir(back).map_err(Error::Dir)?;
+    res
+}
+
+/// point `out` at a new generation
+#[cfg(unix)]
+fn swap(parent: &Path, name: &OsString, out: &Path, generation: &Path) -> Result<(), Error> {
+    // a plain directory from before, moved aside like any other generation
+    if fs::symlink_metadata(out).is_ok_and(|m| !m.is_symlink()) {
+        let aside = generation_name(parent, name, "old")?;
+        fs::rename(out, aside).map_err(Error::Swap)?;
+    }
+
+    // renaming a symlink over the old one is atomic, unlike replacing it
+    let tmp = sibling(parent, name, ".new");
+    _ = fs::remove_file(&tmp);
+    let target = generation.strip_prefix(parent).unwrap_or(generation);
+    std::os::unix::fs::symlink(target, &tmp).map_err(Error::Swap)?;
+    fs::rename(&tmp, out).map_err(Error::Swap)
+}
+
+/// move `out` aside as an old generation, and a new generation into its place
+///
+/// there is a moment where `out` does not exist, as directories cannot be swapped atomically here
+#[cfg(not(unix))]
+fn swap(parent: &Path, name: &OsString, out: &Path, generation: &Path) -> Result<(), Error> {
+    if out.exists() {
+        let aside = generation_name(parent, name, "old")?;
+        fs::rename(out, aside).map_err(Error::Swap)?;
+    }
+    fs::rename(generation, out).map_err(Error::Swap)
+}
+
+/// build a commit into a fresh directory next to `out`, and only swap it into place if that worked
+///
+/// keeps the newest `keep` older builds around, to roll back to by pointing `out` at them
+pub fn build_into(
+    repo: &Repository,
+    commit: &Commit,
+    overrides: &OverrideConfig,
+    config: Option<&ClamConfig>,
+    out: &Path,
+    keep: usize,
+) -> Result<(), Error> {
+    let (parent, name) = split_out(out)?;
+    let id = commit.id().to_string();
+    let generation = generation_name(&parent, &name, &id[..12])?;
+
+    fs::create_dir(&generation).map_err(Error::Dir)?;
+    if let Err(e) = build_in(&generation, repo, commit, overrides, config) {
+        _ = fs::remove_dir_all(&generation);
+        return Err(e);
+    }
+
+    swap(&parent, &name, &parent.join(&name), &generation)?;
+    prune(&parent, &name, &generation, keep)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deploy::*;
+
+    #[test]
+    fn check_is_generation() {
+        let parent = Path::new("/srv");
+        let name = OsString::from("public");
+        let generation = generation_name(parent, &name, "02fbdfcef30c").unwrap();
+        assert!(is_generation(parent, &name, &generation));
+        assert!(!is_generation(
+            parent,
+            &name,
+            &sibling(parent, &name, ".lock")
+        ));
+        assert!(!is_generation(parent, &name, Path::new("/srv/public")));
+        assert!(!is_generation(
+            parent,
+            &name,
+            Path::new("/elsewhere/.public.1-old")
+        ));
+        assert!(!is_generation(
+            parent,
+            &name,
+            Path::new("/srv/.publicity.1-old")
+        ));
+    }
+}
@@ -3,7 +3,10 @@ use percent_encoding::{AsciiSet, CONTROLS};
 use regex::RegexSet;
 use serde::{Deserialize, Deserializer};
 use slugify::slugify;
-use std::path::Path;
+use std::{
+    borrow::Cow,
+    path::{Component, Path, PathBuf},
+};
 use url::Url;
 
 pub const URL_UNSAFE: &AsciiSet = &CONTROLS
@@ -21,15 +24,9 @@ pub const URL_UNSAFE: &AsciiSet = &CONTROLS
 
 pub const URL_PATH_UNSAFE: &AsciiSet = &URL_UNSAFE.add(b'#').add(b'?');
 
-/// run a function on every link in a syntax tree, as a [`Path`]
-///
-/// will skip links to external resources, and adds `index.org` to links to directories, use
-/// [`org_urls`] if you do not want that
-pub fn org_links<F>(res: &Org, name: &Path, mut callback: F)
-where
-    F: FnMut(&Path),
-{
-    let fileroot = Url::from_file_path(
+/// url of a file in the repository, to resolve its links against
+pub fn file_root(name: &Path) -> Url {
+    Url::from_file_path(
         Path::new(
             #[cfg(windows)]
             "H:/",
@@ -38,26 +35,45 @@ where
         )
         .join(name),
     )
-    .expect("current path should fit in a file url");
-    org_urls(res, &fileroot, |url| {
-        if url.scheme() != "file" {
-            return;
-        }
-        let Ok(mut fullpath) = url.to_file_path() else {
-            return;
-        };
-        if url.path().ends_with('/') {
-            fullpath.push("index.org");
-        }
-        let fullpath = fullpath
+    .expect("current path should fit in a file url")
+}
+
+/// turn a url from [`file_root`] back into a path in the repository
+///
+/// adds `index.org` to links to directories, gives [`None`] for external resources
+pub fn repo_path(url: &Url) -> Option<PathBuf> {
+    if url.scheme() != "file" {
+        return None;
+    }
+    let mut fullpath = url.to_file_path().ok()?;
+    if url.path().ends_with('/') {
+        fullpath.push("index.org");
+    }
+    Some(
+        fullpath
             .strip_prefix(
                 #[cfg(windows)]
                 "H:/",
                 #[cfg(not(windows))]
                 "/",
             )
-            .unwrap();
-        callback(fullpath);
+            .unwrap()
+            .to_path_buf(),
+    )
+}
+
+/// run a function on every link in a syntax tree, as a [`Path`]
+///
+/// will skip links to external resources, and adds `index.org` to links to directories, use
+/// [`org_urls`] if you do not want that
+pub fn org_links<F>(res: &Org, name: &Path, mut callback: F)
+where
+    F: FnMut(&Path),
+{
+    org_urls(res, &file_root(name), |url| {
+        if let Some(fullpath) = repo_path(&url) {
+            callback(&fullpath);
+        }
     });
 }
 
@@ -65,6 +81,14 @@ where
 pub fn org_urls<F>(res: &Org, base: &Url, mut callback: F)
 where
     F: FnMut(Url),
+{
+    org_link_urls(res, base, |url, _| callback(url));
+}
+
+/// run a function on every link in a syntax tree, as a [`Url`] along with the link itself
+pub fn org_link_urls<F>(res: &Org, base: &Url, mut callback: F)
+where
+    F: FnMut(Url, &Link),
 {
     let document = res.document();
     let syntax = document.syntax();
@@ -80,8 +104,47 @@ where
         }) else {
             continue;
         };
-        callback(url);
+        callback(url, &link);
+    }
+}
+
+/// resolve `file` relative to the directory `page` is in, without leaving the repository
+pub fn resolve_relative(page: &Path, file: &str) -> Option<PathBuf> {
+    let mut path = page.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    for component in Path::new(file).components() {
+        match component {
+            Component::Normal(c) => path.push(c),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !path.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
     }
+    Some(path)
+}
+
+/// a link to `target` from the directory `page` is in, both relative to the repository
+pub fn relative_link(page: &Path, target: &Path) -> String {
+    let from: Vec<_> = page.parent().map_or(vec![], |p| p.components().collect());
+    let to: Vec<_> = target.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let parts: Vec<_> = from[common..]
+        .iter()
+        .map(|_| Cow::Borrowed(".."))
+        .chain(to[common..].iter().map(|c| c.as_os_str().to_string_lossy()))
+        .collect();
+    if parts.is_empty() {
+        return ".".to_string();
+    }
+    parts.join("/")
+}
+
+/// a [`RegexSet`] that matches everything
+pub fn full_regex_set() -> RegexSet {
+    RegexSet::new(["."]).unwrap()
 }
 
 pub fn de_regex_set<'de, D>(deserializer: D) -> Result<RegexSet, D::Error>
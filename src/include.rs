@@ -0,0 +1,465 @@
+use crate::{
+    git::HistMeta,
+    helpers::{relative_link, resolve_relative},
+};
+use git2::{Repository, Tree};
+use orgize::{
+    ParseConfig, SyntaxKind,
+    ast::{Headline, Keyword, Link},
+    rowan::{NodeOrToken, ast::AstNode},
+};
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+/// files included by each page, including ones included indirectly
+pub type IncludeMap = HashMap<PathBuf, BTreeSet<PathBuf>>;
+
+struct Include<'a> {
+    file: &'a str,
+    search: Option<&'a str>,
+    lines: Option<&'a str>,
+    /// block to wrap the included text in, along with its parameters
+    block: Option<(&'a str, Vec<&'a str>)>,
+}
+
+/// parse the value of an `#+INCLUDE:` or `#+TRANSCLUDE:` keyword
+fn parse_include(value: &str, transclude: bool) -> Option<Include<'_>> {
+    let value = value.trim();
+    let (target, rest) = if transclude {
+        let value = value.strip_prefix("[[")?;
+        let end = value.find(']')?;
+        let rest = &value[end..];
+        let rest = rest.find("]]").map_or("", |i| &rest[i + 2..]);
+        (&value[..end], rest)
+    } else if let Some(value) = value.strip_prefix('"') {
+        let end = value.find('"')?;
+        (&value[..end], &value[end + 1..])
+    } else {
+        value.split_once(char::is_whitespace).unwrap_or((value, ""))
+    };
+    let target = target.strip_prefix("file:").unwrap_or(target);
+    let (file, search) = match target.split_once("::") {
+        Some((file, search)) => (file, Some(search)),
+        None => (target, None),
+    };
+
+    let mut words = rest.split_ascii_whitespace().map(|w| w.trim_matches('"'));
+    let mut lines = None;
+    let mut block: Option<(&str, Vec<&str>)> = None;
+    while let Some(word) = words.next() {
+        if word.eq_ignore_ascii_case(":lines") {
+            lines = words.next();
+        } else if word.starts_with(':') {
+            // some other option we do not know about, and its value
+            words.next();
+        } else if let Some((_, params)) = &mut block {
+            params.push(word);
+        } else {
+            block = Some((word, vec![]));
+        }
+    }
+
+    Some(Include {
+        file,
+        search,
+        lines,
+        block,
+    })
+}
+
+/// find the subtree of a headline, by `*title` or `#custom-id`
+fn find_section<'a>(text: &'a str, search: &str, org_cfg: &ParseConfig) -> Option<&'a str> {
+    let res = org_cfg.clone().parse(text);
+    let headline = res
+        .document()
+        .syntax()
+        .descendants()
+        .filter_map(Headline::cast)
+        .find(|h| {
+            if let Some(title) = search.strip_prefix('*') {
+                h.title_raw().trim() == title.trim()
+            } else if let Some(id) = search.strip_prefix('#') {
+                h.properties()
+                    .and_then(|p| p.get("CUSTOM_ID"))
+                    .is_some_and(|c| c.trim() == id.trim())
+            } else {
+                false
+            }
+        })?;
+    let range = headline.text_range();
+    text.get(usize::from(range.start())..usize::from(range.end()))
+}
+
+/// only keep the lines in a range like `5-10`, counting from 1 and excluding the end
+fn select_lines(text: &str, range: &str) -> Option<String> {
+    let (start, end) = range.split_once('-')?;
+    let start: usize = if start.is_empty() {
+        1
+    } else {
+        start.parse().ok()?
+    };
+    let end: Option<usize> = if end.is_empty() {
+        None
+    } else {
+        Some(end.parse().ok()?)
+    };
+    Some(
+        text.split_inclusive('\n')
+            .enumerate()
+            .filter(|(n, _)| n + 1 >= start && end.is_none_or(|end| n + 1 < end))
+            .map(|(_, l)| l)
+            .collect(),
+    )
+}
+
+/// where the lines with include keywords start, leaving out ones in blocks like the parser does
+fn include_lines(text: &str, org_cfg: &ParseConfig) -> HashSet<usize> {
+    let res = org_cfg.clone().parse(text);
+    res.document()
+        .syntax()
+        .descendants()
+        .filter_map(Keyword::cast)
+        .filter(|k| {
+            let key = k.key();
+            key.eq_ignore_ascii_case("INCLUDE") || key.eq_ignore_ascii_case("TRANSCLUDE")
+        })
+        .map(|k| {
+            let start = usize::from(k.syntax().text_range().start());
+            text[..start].rfind('\n').map_or(0, |i| i + 1)
+        })
+        .collect()
+}
+
+/// whether a link starts with something like `https:`, as opposed to a search like `a.org::x`
+fn has_scheme(link: &str) -> bool {
+    link.split_once(':').is_some_and(|(scheme, rest)| {
+        !scheme.is_empty()
+            && !rest.starts_with(':')
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-'))
+    })
+}
+
+/// rewrite relative links in text from `included`, so they still work from `page`
+fn rebase_links(text: &str, included: &Path, page: &Path, org_cfg: &ParseConfig) -> String {
+    if included.parent() == page.parent() {
+        return text.to_string();
+    }
+
+    let res = org_cfg.clone().parse(text);
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for link in res.document().syntax().descendants().filter_map(Link::cast) {
+        let Some(token) = link
+            .syntax()
+            .children_with_tokens()
+            .filter_map(NodeOrToken::into_token)
+            .find(|t| t.kind() == SyntaxKind::LINK_PATH)
+        else {
+            continue;
+        };
+        let raw = token.text();
+        let (prefix, target) = match raw.strip_prefix("file:") {
+            Some(target) => ("file:", target),
+            None => ("", raw),
+        };
+        // links within the page, absolute paths and other kinds of links stay as they are
+        if target.is_empty()
+            || target.starts_with(['*', '#', '/'])
+            || (prefix.is_empty() && has_scheme(target))
+        {
+            continue;
+        }
+        let split = [target.find('#'), target.find("::")]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(target.len());
+        let (file, rest) = target.split_at(split);
+        let Some(resolved) = resolve_relative(included, file) else {
+            continue;
+        };
+        let mut rebased = relative_link(page, &resolved);
+        if file.ends_with('/') {
+            rebased.push('/');
+        }
+
+        let range = token.text_range();
+        out.push_str(&text[last..range.start().into()]);
+        _ = write!(out, "{prefix}{rebased}{rest}");
+        last = range.end().into();
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+/// the include a line asks for, if any
+fn include_line(line: &str) -> Option<Include<'_>> {
+    let line = line.trim_start().strip_prefix("#+")?;
+    let (key, value) = line.split_once(':')?;
+    if key.eq_ignore_ascii_case("INCLUDE") {
+        parse_include(value, false)
+    } else if key.eq_ignore_ascii_case("TRANSCLUDE") {
+        parse_include(value, true)
+    } else {
+        None
+    }
+}
+
+/// read the text an include points at, complaining if that does not work out
+fn read_include(
+    repo: &Repository,
+    tree: &Tree,
+    org_cfg: &ParseConfig,
+    path: &Path,
+    file: &Path,
+    include: &Include,
+) -> Option<String> {
+    let Some(blob) = tree
+        .get_path(file)
+        .ok()
+        .and_then(|e| e.to_object(repo).ok())
+        .and_then(|o| o.into_blob().ok())
+    else {
+        eprintln!("{}: could not include {}", path.display(), file.display());
+        return None;
+    };
+    let Ok(mut included) = std::str::from_utf8(blob.content()) else {
+        eprintln!(
+            "{}: not including {}, it is not utf-8",
+            path.display(),
+            file.display()
+        );
+        return None;
+    };
+
+    if let Some(search) = include.search {
+        let Some(section) = find_section(included, search, org_cfg) else {
+            eprintln!(
+                "{}: could not find {search} in {}",
+                path.display(),
+                file.display()
+            );
+            return None;
+        };
+        included = section;
+    }
+    if let Some(range) = include.lines {
+        let Some(selected) = select_lines(included, range) else {
+            eprintln!("{}: bad line range {range}", path.display());
+            return None;
+        };
+        return Some(selected);
+    }
+    Some(included.to_string())
+}
+
+fn expand(
+    repo: &Repository,
+    tree: &Tree,
+    org_cfg: &ParseConfig,
+    path: &Path,
+    text: &str,
+    stack: &mut Vec<PathBuf>,
+    deps: &mut BTreeSet<PathBuf>,
+) -> Option<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut changed = false;
+    let keywords = include_lines(text, org_cfg);
+    if keywords.is_empty() {
+        return None;
+    }
+
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let Some(include) = keywords
+            .contains(&start)
+            .then(|| include_line(line))
+            .flatten()
+        else {
+            out.push_str(line);
+            continue;
+        };
+
+        let Some(file) = resolve_relative(path, include.file) else {
+            eprintln!(
+                "{}: not including {}, it is outside of the repository",
+                path.display(),
+                include.file
+            );
+            out.push_str(line);
+            continue;
+        };
+        if stack.contains(&file) {
+            eprintln!(
+                "{}: not including {}, it would include itself",
+                path.display(),
+                file.display()
+            );
+            out.push_str(line);
+            continue;
+        }
+        let Some(included) = read_include(repo, tree, org_cfg, path, &file, &include) else {
+            out.push_str(line);
+            continue;
+        };
+
+        changed = true;
+        deps.insert(file.clone());
+
+        if let Some((kind, params)) = include.block {
+            // blocks are verbatim, no need to look inside them
+            _ = write!(out, "#+begin_{kind}");
+            for param in params {
+                _ = write!(out, " {param}");
+            }
+            out.push('\n');
+            out.push_str(&included);
+            if !included.ends_with('\n') {
+                out.push('\n');
+            }
+            _ = writeln!(out, "#+end_{kind}");
+            continue;
+        }
+
+        stack.push(file.clone());
+        let included =
+            expand(repo, tree, org_cfg, &file, &included, stack, deps).unwrap_or(included);
+        stack.pop();
+        out.push_str(&rebase_links(&included, &file, path, org_cfg));
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+
+    changed.then_some(out)
+}
+
+/// expand `#+INCLUDE:` and `#+TRANSCLUDE:` keywords in an org file from the git tree
+///
+/// the files that ended up included get recorded in `includes`
+pub fn expand_includes<'a>(
+    repo: &Repository,
+    tree: &Tree,
+    org_cfg: &ParseConfig,
+    path: &Path,
+    file: &'a [u8],
+    includes: &mut IncludeMap,
+) -> Cow<'a, [u8]> {
+    if !path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("org"))
+    {
+        return Cow::Borrowed(file);
+    }
+    let Ok(text) = std::str::from_utf8(file) else {
+        return Cow::Borrowed(file);
+    };
+
+    let mut deps = BTreeSet::new();
+    let mut stack = vec![path.to_path_buf()];
+    let Some(expanded) = expand(repo, tree, org_cfg, path, text, &mut stack, &mut deps) else {
+        return Cow::Borrowed(file);
+    };
+    includes.insert(path.to_path_buf(), deps);
+    Cow::Owned(expanded.into_bytes())
+}
+
+/// make edits to included files count as edits to the pages including them
+pub fn apply_includes(hist: &mut HashMap<PathBuf, HistMeta>, includes: &IncludeMap) {
+    for (page, deps) in includes {
+        for dep in deps {
+            let Some(dep) = hist.get(dep) else {
+                continue;
+            };
+            let (modify_time, last_editor, last_commit, last_msg, contributors) = (
+                dep.modify_time,
+                dep.last_editor.clone(),
+                dep.last_commit.clone(),
+                dep.last_msg.clone(),
+                dep.contributors.clone(),
+            );
+            let Some(meta) = hist.get_mut(page) else {
+                continue;
+            };
+            if modify_time.seconds() > meta.modify_time.seconds() {
+                meta.modify_time = modify_time;
+                meta.last_editor = last_editor;
+                meta.last_commit = last_commit;
+                meta.last_msg = last_msg;
+            }
+            meta.contributors.extend(contributors);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::include::*;
+
+    #[test]
+    fn check_parse_include() {
+        let inc = parse_include(r#" "../snippets/a b.org::*contact" :lines "2-" "#, false).unwrap();
+        assert_eq!(inc.file, "../snippets/a b.org");
+        assert_eq!(inc.search, Some("*contact"));
+        assert_eq!(inc.lines, Some("2-"));
+        assert!(inc.block.is_none());
+
+        let inc = parse_include(r#""main.rs" src rust :lines "1-5""#, false).unwrap();
+        assert_eq!(inc.block, Some(("src", vec!["rust"])));
+
+        let inc = parse_include(" [[file:other.org::#id][desc]] :lines 3-4", true).unwrap();
+        assert_eq!(inc.file, "other.org");
+        assert_eq!(inc.search, Some("#id"));
+        assert_eq!(inc.lines, Some("3-4"));
+    }
+
+    #[test]
+    fn check_include_lines() {
+        let text = "#+INCLUDE: a.org\n#+begin_src org\n#+INCLUDE: b.org\n#+end_src\n  #+transclude: [[c.org]]\n";
+        let mut found: Vec<_> = include_lines(text, &ParseConfig::default())
+            .into_iter()
+            .collect();
+        found.sort_unstable();
+        assert_eq!(found, [0, 60]);
+    }
+
+    #[test]
+    fn check_rebase_links() {
+        let text = "[[other.org][o]] [[file:../up.org::*x]] [[sub/deep.org#y]] [[https://a.b/c]] [[#here]] [[/abs.org]]\n";
+        assert_eq!(
+            rebase_links(
+                text,
+                Path::new("snippets/contact.org"),
+                Path::new("blog/post.org"),
+                &ParseConfig::default()
+            ),
+            "[[../snippets/other.org][o]] [[file:../up.org::*x]] [[../snippets/sub/deep.org#y]] [[https://a.b/c]] [[#here]] [[/abs.org]]\n"
+        );
+        assert_eq!(
+            rebase_links(
+                "[[a.org]]",
+                Path::new("snippets/contact.org"),
+                Path::new("index.org"),
+                &ParseConfig::default()
+            ),
+            "[[snippets/a.org]]"
+        );
+    }
+
+    #[test]
+    fn check_select_lines() {
+        let text = "a\nb\nc\nd\n";
+        assert_eq!(select_lines(text, "2-4").unwrap(), "b\nc\n");
+        assert_eq!(select_lines(text, "-2").unwrap(), "a\n");
+        assert_eq!(select_lines(text, "3-").unwrap(), "c\nd\n");
+        assert!(select_lines(text, "3").is_none());
+    }
+}
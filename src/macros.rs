@@ -0,0 +1,190 @@
+use crate::config::ClamConfig;
+use git2::{Repository, Tree};
+use orgize::{Org, ParseConfig, ast::Macros, rowan::ast::AstNode};
+use std::{borrow::Cow, collections::HashMap, path::Path};
+
+/// macro templates, by name
+pub type MacroMap = HashMap<String, String>;
+
+/// how many times expanded text may get expanded again, so recursive macros stop eventually
+const MAX_DEPTH: usize = 16;
+
+/// add the `#+MACRO:` definitions of a document
+fn collect_macros(res: &Org, macros: &mut MacroMap) {
+    for keyword in res.keywords() {
+        if !keyword.key().eq_ignore_ascii_case("MACRO") {
+            continue;
+        }
+        let value = keyword.value();
+        let value = value.trim();
+        let (name, template) = value.split_once(char::is_whitespace).unwrap_or((value, ""));
+        macros.insert(name.to_string(), template.trim().to_string());
+    }
+}
+
+/// macros from `.clam.toml` and the `macro_file` it points at
+pub fn site_macros(
+    repo: &Repository,
+    tree: &Tree,
+    config: Option<&ClamConfig>,
+    org_cfg: &ParseConfig,
+) -> MacroMap {
+    let mut macros = MacroMap::new();
+    let Some(config) = config else {
+        return macros;
+    };
+
+    if let Some(path) = &config.macro_file {
+        if let Some(blob) = tree
+            .get_path(path)
+            .ok()
+            .and_then(|e| e.to_object(repo).ok())
+            .and_then(|o| o.into_blob().ok())
+            && let Ok(text) = std::str::from_utf8(blob.content())
+        {
+            collect_macros(&org_cfg.clone().parse(text), &mut macros);
+        } else {
+            eprintln!("could not read macros from {}", path.display());
+        }
+    }
+    // the config file wins over the macro file
+    for (name, template) in &config.macros {
+        macros.insert(name.clone(), template.clone());
+    }
+
+    macros
+}
+
+/// split macro arguments on commas, unless they are escaped
+fn split_args(args: &str) -> Vec<String> {
+    let mut out = vec![String::new()];
+    let mut chars = args.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&',') => {
+                out.last_mut().unwrap().push(',');
+                chars.next();
+            }
+            ',' => out.push(String::new()),
+            c => out.last_mut().unwrap().push(c),
+        }
+    }
+    out.into_iter().map(|a| a.trim().to_string()).collect()
+}
+
+/// fill in the `$1`-style placeholders of a template
+fn substitute(template: &str, args: &[String]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if let Ok(n) = rest[..digits].parse::<usize>() {
+            if let Some(arg) = n.checked_sub(1).and_then(|n| args.get(n)) {
+                out.push_str(arg);
+            }
+        } else {
+            out.push('$');
+        }
+        rest = &rest[digits..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// expand user-defined macros in an org file, before it gets parsed for real
+///
+/// definitions in the file itself take priority over the ones in `site`. macros clam handles
+/// itself are left alone unless something defines them
+pub fn expand_macros<'a>(
+    path: &Path,
+    file: &'a [u8],
+    site: &MacroMap,
+    org_cfg: &ParseConfig,
+) -> Cow<'a, [u8]> {
+    if !path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("org"))
+    {
+        return Cow::Borrowed(file);
+    }
+    let Ok(text) = std::str::from_utf8(file) else {
+        return Cow::Borrowed(file);
+    };
+    if !text.contains("{{{") {
+        return Cow::Borrowed(file);
+    }
+
+    let mut text = Cow::Borrowed(text);
+    for _ in 0..MAX_DEPTH {
+        let res = org_cfg.clone().parse(&*text);
+        let mut macros = Cow::Borrowed(site);
+        if res
+            .keywords()
+            .any(|k| k.key().eq_ignore_ascii_case("MACRO"))
+        {
+            collect_macros(&res, macros.to_mut());
+        }
+
+        let mut out = String::with_capacity(text.len());
+        let mut last = 0;
+        for call in res
+            .document()
+            .syntax()
+            .descendants()
+            .filter_map(Macros::cast)
+        {
+            let Some(template) = macros.get(call.key().as_ref()) else {
+                continue;
+            };
+            let args = call.args().map(|a| split_args(&a)).unwrap_or_default();
+            let range = call.syntax().text_range();
+            out.push_str(&text[last..range.start().into()]);
+            out.push_str(&substitute(template, &args));
+            last = range.end().into();
+        }
+        if last == 0 {
+            return match text {
+                Cow::Borrowed(_) => Cow::Borrowed(file),
+                Cow::Owned(text) => Cow::Owned(text.into_bytes()),
+            };
+        }
+        out.push_str(&text[last..]);
+        text = Cow::Owned(out);
+    }
+
+    eprintln!("macros nested too deeply, some were left unexpanded");
+    Cow::Owned(text.into_owned().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::macros::*;
+
+    #[test]
+    fn check_expand_macros() {
+        let site = MacroMap::from([
+            ("greet".to_string(), "hello $1, from $2$3".to_string()),
+            ("loud".to_string(), "*{{{greet($1, me)}}}*".to_string()),
+        ]);
+        let file = b"#+MACRO: local $1 is local
+{{{greet(fox\\, the, you)}}} {{{loud(world)}}} {{{local(this)}}} {{{n}}}
+#+begin_src org
+{{{greet(not, expanded)}}}
+#+end_src
+";
+        let expanded = expand_macros(Path::new("meow.org"), file, &site, &ParseConfig::default());
+        assert_eq!(
+            str::from_utf8(&expanded).unwrap(),
+            "#+MACRO: local $1 is local
+hello fox, the, from you *hello world, from me* this is local {{{n}}}
+#+begin_src org
+{{{greet(not, expanded)}}}
+#+end_src
+"
+        );
+    }
+}
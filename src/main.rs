@@ -5,14 +5,19 @@ use clap::{Args, Parser, Subcommand};
 use foxerror::FoxError;
 use git2::{Commit, Repository};
 use orgize::config::{ParseConfig, UseSubSuperscript};
-use std::{collections::HashMap, env::set_current_dir, fs, io::Write, path::PathBuf};
+use std::{collections::HashMap, env::set_current_dir, fs, io::Write, path::PathBuf, rc::Rc};
 
 mod atom;
 mod config;
+mod deploy;
 mod git;
 mod helpers;
+mod include;
+mod macros;
 mod output;
+mod postreceive;
 mod prereceive;
+mod signers;
 #[cfg(feature = "util")]
 mod util;
 
@@ -25,27 +30,37 @@ struct Opt {
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// generate site from git repository
-    Build(RepoArgs),
+    Build(BuildArgs),
     /// serve the current directory in limited preview mode
     #[cfg(feature = "util")]
     Preview(PreviewArgs),
     /// check for orphan pages
     #[cfg(feature = "util")]
-    Orphan(RepoArgs),
+    Orphan(CheckArgs),
     /// check for links to missing pages
     #[cfg(feature = "util")]
-    Redlink(RepoArgs),
+    Redlink(CheckArgs),
+    /// check for links to external pages that are broken
+    #[cfg(feature = "util")]
+    Linkcheck(LinkcheckArgs),
     /// output page content as json lines
     #[cfg(feature = "util")]
     Jsonindex(RepoArgs),
     /// output links between pages in graphviz dot format
     #[cfg(feature = "util")]
     Dot(RepoArgs),
-    /// output page content as a single html file
+    /// output page content as a single file
+    #[cfg(feature = "util")]
+    Single(SingleArgs),
+    /// output page content as an epub file
     #[cfg(feature = "util")]
-    Single(RepoArgs),
+    Epub(RepoArgs),
     /// hook for filtering incoming git pushes
     PreReceive(PreReceiveArgs),
+    /// check a range of commits against the pre-receive rules, as if it were pushed
+    CheckPush(CheckPushArgs),
+    /// hook for building the site when a branch is pushed to
+    PostReceive(PostReceiveArgs),
 }
 
 #[derive(Debug, Args)]
@@ -68,6 +83,20 @@ struct RepoArgs {
     inline: Option<bool>,
 }
 
+#[derive(Debug, Args)]
+struct BuildArgs {
+    #[command(flatten)]
+    repo: RepoArgs,
+    /// build into a fresh directory next to this one, then replace it with a symlink to that
+    ///
+    /// nothing is replaced if the build fails
+    #[arg(long, value_name = "DIR")]
+    out: Option<PathBuf>,
+    /// how many older builds to keep next to --out, for rolling back
+    #[arg(long, default_value_t = 3, requires = "out")]
+    keep: usize,
+}
+
 #[cfg(feature = "util")]
 #[derive(Debug, Args)]
 struct PreviewArgs {
@@ -75,18 +104,92 @@ struct PreviewArgs {
     bindhost: std::net::SocketAddr,
 }
 
+#[cfg(feature = "util")]
+#[derive(Debug, Args)]
+struct SingleArgs {
+    #[command(flatten)]
+    repo: RepoArgs,
+    /// largest local image to embed as a data uri, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 1 << 20)]
+    max_image_size: usize,
+    /// output plain text, ignoring format
+    #[arg(long)]
+    plain: bool,
+}
+
+#[cfg(feature = "util")]
+#[derive(Debug, Args)]
+struct CheckArgs {
+    #[command(flatten)]
+    repo: RepoArgs,
+    /// output problems as json lines
+    #[arg(long, conflicts_with = "sarif")]
+    json: bool,
+    /// output problems as a sarif log
+    #[arg(long)]
+    sarif: bool,
+}
+
+#[cfg(feature = "util")]
+#[derive(Debug, Args)]
+struct LinkcheckArgs {
+    #[command(flatten)]
+    check: CheckArgs,
+    /// how many links to check at once
+    #[arg(long, default_value_t = 8)]
+    jobs: usize,
+    /// time to wait between requests to the same host
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 1000)]
+    host_delay: u64,
+    /// how many times to retry links that look like they are only broken temporarily
+    #[arg(long, default_value_t = 2)]
+    retries: u32,
+    /// remember results in this file, and reuse them next time
+    #[arg(long, value_name = "FILE")]
+    cache: Option<PathBuf>,
+    /// how long working links stay in the cache before being checked again
+    #[arg(long, value_name = "SECONDS", default_value_t = 7 * 24 * 60 * 60)]
+    max_age: u64,
+    /// do not check urls matching this set of regexes
+    ///
+    /// may be specified multiple times for multiple patterns
+    #[arg(long, value_name = "PATTERN")]
+    allow: Vec<String>,
+    /// only use results from the cache, without touching the network
+    #[arg(long, requires = "cache")]
+    offline: bool,
+}
+
 #[derive(Debug, Args)]
 #[allow(clippy::struct_excessive_bools)]
 struct PreReceiveArgs {
-    /// require commits to be signed (does not verify signatures)
+    /// take rules from this toml file, picking them by pusher and ref
+    ///
+    /// pushers are named by the environment gitolite or a web server sets up, or identified by
+    /// the fingerprint of the key signing each commit. when given, the rule flags below are
+    /// ignored
+    #[arg(long, value_name = "FILE")]
+    rules: Option<PathBuf>,
+    /// require commits to be signed (only verified with --allowed-signers)
     #[arg(long)]
     require_signing: bool,
+    /// require commits to have ssh signatures from keys in this file
+    ///
+    /// uses the same format as git's gpg.ssh.allowedSignersFile
+    #[arg(long, value_name = "FILE")]
+    allowed_signers: Option<PathBuf>,
+    /// require the principals of the signing key to match the committer email
+    #[arg(long, requires = "allowed_signers")]
+    match_committer: bool,
     /// do not allow any pages to be deleted
     #[arg(long)]
     no_deletion: bool,
     /// do not allow any new pages to be created
     #[arg(long)]
     no_creation: bool,
+    /// do not allow turning files into symlinks or submodules, or the other way around
+    #[arg(long)]
+    no_typechange: bool,
     /// do not allow new merge commits
     #[arg(long)]
     no_merges: bool,
@@ -100,12 +203,106 @@ struct PreReceiveArgs {
     /// may be specified multiple times for multiple patterns
     #[arg(long, value_name = "PATTERN")]
     protect_pattern: Vec<String>,
+    /// do not allow org files that are not valid utf-8
+    #[arg(long)]
+    require_utf8: bool,
+    /// do not allow links to pages that do not exist
+    ///
+    /// links that were already broken before the push are fine
+    #[arg(long)]
+    no_new_redlinks: bool,
+    /// require pages to have a #+TITLE
+    #[arg(long)]
+    require_title: bool,
+    /// do not allow new files with the same slug as another file
+    #[arg(long)]
+    unique_slugs: bool,
+    /// largest file that may be pushed, in bytes
+    #[arg(long, value_name = "BYTES")]
+    max_blob_size: Option<u64>,
+    /// most new data a single push may contain, in bytes
+    #[arg(long, value_name = "BYTES")]
+    max_push_size: Option<u64>,
+    /// only allow new files with these extensions
+    ///
+    /// may be specified multiple times. an empty extension allows files without one
+    #[arg(long, value_name = "EXT")]
+    allow_extension: Vec<String>,
+    /// do not allow new files with these extensions
+    ///
+    /// may be specified multiple times
+    #[arg(long, value_name = "EXT")]
+    deny_extension: Vec<String>,
+    /// only allow new files that look like these mime types, like image/png or image/*
+    ///
+    /// may be specified multiple times
+    #[arg(long, value_name = "TYPE")]
+    allow_mime: Vec<String>,
+    /// do not allow new files that look like these mime types
+    ///
+    /// may be specified multiple times
+    #[arg(long, value_name = "TYPE")]
+    deny_mime: Vec<String>,
+    /// allow creating refs matching this pattern, like refs/heads/draft/*
+    ///
+    /// commits on new refs are checked from where they branched off the default branch. may be
+    /// specified multiple times
+    #[arg(long, value_name = "PATTERN")]
+    allow_create_ref: Vec<String>,
+    /// allow deleting refs matching this pattern
+    ///
+    /// may be specified multiple times
+    #[arg(long, value_name = "PATTERN")]
+    allow_delete_ref: Vec<String>,
+    /// most problems to list when rejecting a push
+    #[arg(long, value_name = "COUNT", default_value_t = 50)]
+    report_limit: usize,
+}
+
+#[derive(Debug, Args)]
+struct CheckPushArgs {
+    /// commits to check, like OLD..NEW, or a single commit to check it as a new ref
+    range: String,
+    /// ref the commits would be pushed to, instead of the one NEW names
+    #[arg(long = "ref", value_name = "REFNAME")]
+    refname: Option<String>,
+    /// pretend to be this pusher when picking rules
+    #[arg(long)]
+    user: Option<String>,
+    #[command(flatten)]
+    rules: PreReceiveArgs,
+}
+
+#[derive(Debug, Args)]
+struct PostReceiveArgs {
+    /// build into this directory, which gets replaced by a symlink to the newest build
+    #[arg(long, value_name = "DIR")]
+    out: PathBuf,
+    /// branch to build when pushed to
+    #[arg(long, default_value = "HEAD")]
+    branch: String,
+    /// how many older builds to keep next to --out, for rolling back
+    #[arg(long, default_value_t = 3)]
+    keep: usize,
+    /// output format
+    #[arg(long, default_value = "html")]
+    format: OutputFormat,
+    /// override base url in feeds
+    #[arg(long)]
+    url: Option<String>,
+    /// override inlining css
+    #[arg(long)]
+    inline: Option<bool>,
 }
 
 #[derive(Debug, FoxError)]
 enum Error {
     /// invalid input. this is being used as a git hook, yes?
     InvalidHookInput,
+    /// could not tell which ref this would be pushed to, try --ref
+    UnknownRef,
+    /// not a range of commits
+    BadRange,
     /// force-pushes are not permitted
     ForcePush,
     /// paths that are not utf-8 are not supported
@@ -114,18 +311,53 @@ enum Error {
     NonUTF8Org(std::str::Utf8Error),
     /// signing your commits is required
     NotSigned,
+    /// commit signature is invalid
+    BadSignature,
+    /// commit signed by untrusted key
+    UntrustedSigner(String),
+    /// signing key is not allowed to sign as the committer
+    SignerMismatch(String),
+    /// failed to read allowed signers
+    AllowedSigners(std::io::Error),
+    /// failed to read rules
+    RulesFile(std::io::Error),
+    /// failed to parse rules
+    #[err(from)]
+    BadRules(toml_edit::de::Error),
+    /// no rule applies to pushing to this ref
+    NoRule(String),
+    /// denied by rule
+    DeniedBy(String, Box<Self>),
+    /// page is not valid utf-8
+    BadUtf8Page(String),
+    /// link to a page that does not exist
+    NewRedlink(String),
+    /// page has no title
+    MissingTitle(String),
+    /// another file has the same slug
+    SlugCollision(String),
+    /// file is too big
+    BlobTooBig(String),
+    /// push is too big
+    PushTooBig(String),
+    /// file type is not permitted
+    BadFileType(String),
     /// merge commits are not permitted
     Merge,
     /// deleting pages is not permitted
     BadDelete(String),
     /// creating pages is not permitted
     BadCreate(String),
+    /// changing the type of files is not permitted
+    BadTypeChange(String),
     /// editing this page is not permitted
     NotAllowed(String),
     /// page is protected
     Protected(String),
-    /// creating new refs is not permitted
+    /// creating this ref is not permitted
     CreateRef(String),
+    /// deleting this ref is not permitted
+    DeleteRef(String),
     /// failed to compile regex
     #[err(from)]
     BadRegex(regex::Error),
@@ -138,6 +370,10 @@ enum Error {
     File(std::io::Error),
     /// failed to write directory
     Dir(std::io::Error),
+    /// failed to lock output directory
+    Lock(std::io::Error),
+    /// failed to swap build into place
+    Swap(std::io::Error),
     /// your system clock is screwed
     Clock(std::time::SystemTimeError),
     /// missing history of file
@@ -163,6 +399,9 @@ enum Error {
     /// page with duplicate slug exists, overwriting it
     #[cfg(feature = "util")]
     SlugExists(String),
+    /// epub does not fit in a zip file
+    #[cfg(feature = "util")]
+    ZipTooBig(String),
 }
 
 #[derive(Clone, Copy, Debug, clap::ValueEnum)]
@@ -187,13 +426,14 @@ static STYLEFEED: &[u8] = include_bytes!("style.xsl");
 fn generate(
     repo: &Repository,
     commit: &Commit,
-    overrides: config::OverrideConfig,
+    overrides: &config::OverrideConfig,
+    config: Option<&config::ClamConfig>,
 ) -> Result<(), Error> {
     let oid = commit.id();
     let tree = commit.tree().unwrap();
     let format = overrides.format;
 
-    let hmeta = git::make_time_tree(repo, oid)?;
+    let mut hmeta = git::make_time_tree(repo, oid)?;
 
     {
         let mut f = fs::File::create("style.css").map_err(Error::File)?;
@@ -206,17 +446,31 @@ fn generate(
     let mut links = HashMap::new();
     // TODO: get this stuff from .clam.toml or something
     let org_cfg = default_org_cfg();
+    let buckets = config.map_or(&[][..], |c| &c.site_buckets);
+    let site_macros = macros::site_macros(repo, &tree, config, &org_cfg);
+    let site = Rc::new(output::site_index(
+        repo,
+        &tree,
+        &org_cfg,
+        buckets,
+        &site_macros,
+    )?);
+    let mut includes = include::IncludeMap::new();
 
     tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
         if let Err(e) = git::walk_callback(repo, dir, entry, true, |name, blob| {
-            crate::output::generate_page(
-                format,
-                dir,
-                name,
-                blob.content(),
+            let path = PathBuf::from(format!("{dir}{name}"));
+            let file = include::expand_includes(
+                repo,
+                &tree,
                 &org_cfg,
-                &mut pages,
-                &mut links,
+                &path,
+                blob.content(),
+                &mut includes,
+            );
+            let file = macros::expand_macros(&path, &file, &site_macros, &org_cfg);
+            crate::output::generate_page(
+                format, dir, name, &file, &org_cfg, &mut pages, &mut links, &site,
             )
         }) {
             eprintln!("{e}");
@@ -224,12 +478,15 @@ fn generate(
         0
     })?;
 
-    let config = config::handle_config(&pages, &hmeta, overrides);
-    if config.is_none() {
+    include::apply_includes(&mut hmeta, &includes);
+
+    if let Some(config) = config {
+        config::handle_config(config, &pages, &hmeta, format);
+    } else {
         eprintln!("configless, no feeds generated and overrides ignored");
     }
 
-    output::write_org_page(format, &pages, &hmeta, &links, config.as_ref())?;
+    output::write_org_page(format, &pages, &hmeta, &links, config)?;
 
     Ok(())
 }
@@ -238,13 +495,23 @@ fn main() {
     let opt = Opt::parse();
 
     match &opt.command {
-        Commands::Build(args) => open_repo(args, |r, c| do_build(r, c, args)),
+        Commands::Build(args) => open_repo(&args.repo, |r, c| do_build(r, c, args)),
         #[cfg(feature = "util")]
         Commands::Preview(args) => do_preview(args),
         #[cfg(feature = "util")]
-        Commands::Orphan(args) => open_repo(args, util::orphan::print_orphans),
+        Commands::Orphan(args) => {
+            open_repo(&args.repo, |r, c| util::orphan::print_orphans(r, c, args));
+        }
+        #[cfg(feature = "util")]
+        Commands::Redlink(args) => {
+            open_repo(&args.repo, |r, c| util::redlink::print_redlinks(r, c, args));
+        }
         #[cfg(feature = "util")]
-        Commands::Redlink(args) => open_repo(args, util::redlink::print_redlinks),
+        Commands::Linkcheck(args) => {
+            open_repo(&args.check.repo, |r, c| {
+                util::linkcheck::print_linkcheck(r, c, args);
+            });
+        }
         #[cfg(feature = "util")]
         Commands::Jsonindex(args) => {
             open_repo(args, |r, c| util::jsonindex::print_index(r, c, args));
@@ -252,19 +519,32 @@ fn main() {
         #[cfg(feature = "util")]
         Commands::Dot(args) => open_repo(args, |r, c| util::dot::print_dot(r, c, args)),
         #[cfg(feature = "util")]
-        Commands::Single(args) => open_repo(args, util::single::print_html),
+        Commands::Single(args) => {
+            open_repo(&args.repo, |r, c| util::single::print_single(r, c, args));
+        }
+        #[cfg(feature = "util")]
+        Commands::Epub(args) => open_repo(args, |r, c| util::epub::print_epub(r, c, args)),
         Commands::PreReceive(args) => prereceive::hook(args),
+        Commands::CheckPush(args) => prereceive::check_push(args),
+        Commands::PostReceive(args) => postreceive::hook(args),
     }
 }
 
-fn do_build(repo: &Repository, commit: &Commit, args: &RepoArgs) {
+fn do_build(repo: &Repository, commit: &Commit, args: &BuildArgs) {
     let overrides = config::OverrideConfig {
-        url: args.url.clone(),
-        inline: args.inline,
-        format: args.format,
+        url: args.repo.url.clone(),
+        inline: args.repo.inline,
+        format: args.repo.format,
     };
 
-    if let Err(e) = generate(repo, commit, overrides) {
+    let config = config::read_config(&overrides);
+    let res = match &args.out {
+        Some(out) => deploy::with_lock(out, || {
+            deploy::build_into(repo, commit, &overrides, config.as_ref(), out, args.keep)
+        }),
+        None => generate(repo, commit, &overrides, config.as_ref()),
+    };
+    if let Err(e) = res {
         eprintln!("failed to generate: {e}");
         std::process::exit(1);
     }
@@ -4,14 +4,16 @@ use crate::{
     git::HistMeta,
     helpers::{URL_PATH_UNSAFE, org_links},
     output::{
-        NodeOrToken, Page, PageMetadata, TokenList, accumulate, get_keywords, infer_title,
-        mangle_link,
+        NodeOrToken, Page, PageMetadata, SiteIndex, TocKind, TokenList, accumulate, get_keywords,
+        infer_title, mangle_link, named_captions, number_labels, pages_under, resolve_nref,
+        stale_notice, step_counter, year_ago,
     },
 };
 use chrono::{DateTime, Datelike};
+use indexmap::IndexMap;
 use orgize::{
     ParseConfig, SyntaxKind,
-    ast::{Token, filter_token},
+    ast::{Headline, Keyword, Token, filter_token},
     export::{Container, Event, TraversalContext, Traverser},
     rowan::ast::AstNode,
 };
@@ -46,12 +48,23 @@ enum LinkLabel {
     Description(TokenList),
 }
 
+/// like [`super::Relink`], but for gemtext, which may have nothing to link to
+pub type GmiRelink = Box<dyn Fn(&str) -> Option<String>>;
+
 #[derive(Default)]
 pub struct GmiExport {
     output: String,
     links: Vec<LinkLine>,
     nums: BTreeMap<String, u64>,
-    accumulated: BTreeMap<String, Vec<String>>,
+    labels: HashMap<String, u64>,
+    feet: IndexMap<String, Option<TokenList>>,
+    pub accumulated: BTreeMap<String, Vec<String>>,
+    /// rewrite link targets with this instead of pointing them at other gmi files, links it
+    /// gives [`None`] for are left as plain text
+    pub relink: Option<GmiRelink>,
+    pub site: Option<Rc<SiteIndex>>,
+    /// path of the org file being exported
+    pub path: PathBuf,
 }
 
 impl GmiExport {
@@ -77,9 +90,15 @@ impl GmiExport {
         }
         if !self.links.is_empty() {
             let links = std::mem::take(&mut self.links);
+            let mut linked = false;
             for LinkLine { path, label } in links {
+                // the text of the link is in the paragraph already
+                let Some(path) = self.link_target(&path) else {
+                    continue;
+                };
+                linked = true;
                 self.push_str("=> ");
-                self.push_str(mangle_link(&path, ".gmi", ".gmi#"));
+                self.push_str(path);
                 match label {
                     LinkLabel::Path => (),
                     LinkLabel::Caption(c) => {
@@ -96,12 +115,81 @@ impl GmiExport {
                 self.output.push('\n');
             }
 
-            self.output.push('\n');
+            if linked {
+                self.output.push('\n');
+            }
         }
     }
     pub fn finish(self) -> String {
         self.output
     }
+    fn link_target(&self, path: &str) -> Option<String> {
+        match &self.relink {
+            Some(relink) => relink(path),
+            None => Some(mangle_link(path, ".gmi", ".gmi#")),
+        }
+    }
+    fn output_toc(&mut self, keyword: &Keyword, ctx: &mut TraversalContext) {
+        let value = keyword.value();
+        match TocKind::parse(&value) {
+            Some(TocKind::Headlines(limit)) => self.output_headline_toc(keyword, limit, ctx),
+            Some(kind @ (TocKind::Tables | TocKind::Listings)) => {
+                if let Some(root) = keyword.syntax().ancestors().last() {
+                    // nothing to link to, but knowing what is there is still nice
+                    let captions = named_captions(&root, matches!(kind, TocKind::Listings));
+                    for (_, caption) in captions {
+                        self.push_str(format!("* {caption}\n"));
+                    }
+                    self.next(ctx);
+                }
+            }
+            Some(TocKind::Pages(dir)) => {
+                let Some(site) = self.site.clone() else {
+                    return;
+                };
+                for (path, title) in pages_under(&site.titles, &self.path, dir)
+                    .into_iter()
+                    .flatten()
+                {
+                    match self.link_target(&path) {
+                        Some(path) => self.push_str(format!("=> {path} {title}\n")),
+                        None => self.push_str(format!("* {title}\n")),
+                    }
+                }
+                self.next(ctx);
+            }
+            None => (),
+        }
+    }
+    /// output an indented outline of the headlines, since gemtext has no in-page anchors to
+    /// link to
+    fn output_headline_toc(&mut self, keyword: &Keyword, limit: usize, ctx: &mut TraversalContext) {
+        let Some(Some(parent)) = keyword.syntax().parent().map(|p| p.parent()) else {
+            return;
+        };
+        // links in headline titles do not belong after the outline
+        let links = std::mem::take(&mut self.links);
+        for headline in parent.descendants().filter_map(Headline::cast) {
+            let level = headline.level();
+            if limit != 0 && level > limit {
+                continue;
+            }
+            for _ in 1..level {
+                self.push_str("  ");
+            }
+            self.push_str("* ");
+            if let Some(keyword) = headline.todo_keyword() {
+                self.push_str(&keyword);
+                self.output.push(' ');
+            }
+            for e in headline.title() {
+                self.element(e, ctx);
+            }
+            self.output.push('\n');
+        }
+        self.links = links;
+        self.next(ctx);
+    }
     /// output children while stripping off some exterior formatting
     fn output_block_children(
         &mut self,
@@ -323,16 +411,90 @@ impl Traverser for GmiExport {
                 ctx.skip();
             }
             Event::Enter(Container::Keyword(keyword)) => {
-                if keyword.key().eq_ignore_ascii_case("CUM")
-                    && let Some(bucket) = self.accumulated.get(keyword.value().trim())
-                {
-                    for name in bucket {
-                        self.output += format!("- {name}\n").as_ref();
+                if keyword.key().eq_ignore_ascii_case("TOC") {
+                    self.output_toc(&keyword, ctx);
+                } else if keyword.key().eq_ignore_ascii_case("CUM") {
+                    let value = keyword.value();
+                    let bucket = value.trim();
+                    if let Some(site) = self.site.clone()
+                        && let Some(entries) = site.buckets.get(bucket)
+                    {
+                        for entry in entries {
+                            match self.link_target(&entry.link(&self.path)) {
+                                Some(link) => self.push_str(format!("=> {link} {}\n", entry.name)),
+                                None => self.push_str(format!("* {}\n", entry.name)),
+                            }
+                        }
+                        self.output += "\n";
+                    } else if let Some(bucket) = self.accumulated.get(bucket) {
+                        for name in bucket {
+                            self.output += format!("- {name}\n").as_ref();
+                        }
+                        self.output += "\n";
                     }
-                    self.output += "\n";
                 }
                 ctx.skip();
             }
+            // same footnote parsing as the html output, see the FIXME there
+            Event::Enter(Container::FnDef(foot)) => {
+                let mut children = foot.syntax().children_with_tokens().skip(3);
+                if let Some(Some(name)) = children.next().map(NodeOrToken::into_token)
+                    && children
+                        .next()
+                        .is_some_and(|c| c.kind() == SyntaxKind::R_BRACKET)
+                {
+                    self.feet
+                        .insert(name.text().to_string(), Some(children.collect()));
+                }
+                ctx.skip();
+            }
+            Event::Enter(Container::FnRef(foot)) => {
+                let mut children = foot.syntax().children_with_tokens().skip(3);
+                if let Some(Some(name)) = children.next().map(NodeOrToken::into_token) {
+                    let name = name.text();
+                    let def = children
+                        .next()
+                        .is_some_and(|c| c.kind() == SyntaxKind::COLON);
+                    let fnum = if let Some((n, _, note)) = self.feet.get_full_mut(name) {
+                        if def {
+                            *note = Some(children.collect());
+                        }
+                        n
+                    } else {
+                        let n = self.feet.len();
+                        self.feet.insert(
+                            if name.is_empty() {
+                                format!(":{n}") // footnote name cannot contain colons
+                            } else {
+                                name.to_string()
+                            },
+                            if def { Some(children.collect()) } else { None },
+                        );
+                        n
+                    };
+                    self.push_str(format!("[{}]", fnum + 1));
+                }
+                ctx.skip();
+            }
+            Event::Enter(Container::Document(document)) => {
+                self.labels = number_labels(document.syntax());
+            }
+            Event::Leave(Container::Document(_)) if !self.feet.is_empty() => {
+                self.next(ctx);
+                self.push_str("## footnotes\n\n");
+                let feet = std::mem::take(&mut self.feet);
+                for (n, elem) in feet.values().enumerate() {
+                    let before = std::mem::take(&mut self.output);
+                    for e in elem.iter().flatten() {
+                        self.element(e.clone(), ctx);
+                    }
+                    let text = std::mem::replace(&mut self.output, before);
+                    self.push_str(format!("[{}] ", n + 1));
+                    self.push_join(text.trim());
+                    self.output.push('\n');
+                }
+                self.next(ctx);
+            }
             Event::Enter(Container::CommentBlock(_)) => ctx.skip(),
             Event::Text(text) => self.push_join(text),
             Event::Timestamp(timestamp) => {
@@ -344,24 +506,27 @@ impl Traverser for GmiExport {
             Event::Macros(macros) => match macros.key().as_ref() {
                 "n" => {
                     let args = macros.args();
-                    let args: Option<&str> = args.as_deref();
-                    let (name, act) = args
-                        .and_then(|a| a.split_once(','))
-                        .unwrap_or_else(|| (args.unwrap_or(""), ""));
-                    let (name, act) = (name.trim(), act.trim());
-                    let num = if matches!(act, "" | "-") {
-                        self.nums
-                            .get(name)
-                            .copied()
-                            .map(|n| if act == "-" { n } else { n.wrapping_add(1) })
+                    let (num, _) = step_counter(&mut self.nums, args.as_deref());
+                    self.push_str(format!("{num}"));
+                }
+                "nref" => {
+                    // gemtext has no inline links, so just the number
+                    let args = macros.args();
+                    if let Some((_, num)) = resolve_nref(
+                        &self.path,
+                        args.as_deref(),
+                        &self.labels,
+                        self.site.as_deref(),
+                    ) {
+                        self.push_str(format!("{num}"));
                     } else {
-                        act.parse().ok()
+                        eprintln!(
+                            "{}: unknown counter label {}",
+                            self.path.display(),
+                            args.as_deref().unwrap_or("")
+                        );
+                        self.push_str("??");
                     }
-                    .unwrap_or(1);
-
-                    self.push_str(format!("{num}"));
-
-                    self.nums.insert(name.to_string(), num);
                 }
                 "cum" => {
                     if let Some(args) = macros.args()
@@ -392,6 +557,7 @@ pub fn generate_page(
     org_cfg: &ParseConfig,
     pages: &mut HashMap<PathBuf, Page>,
     links: &mut HashMap<PathBuf, Vec<Rc<PathBuf>>>,
+    site: &Rc<SiteIndex>,
 ) -> Result<(), Error> {
     let mut full_path: PathBuf = format!("{dir}{name}").into();
     if full_path
@@ -422,6 +588,8 @@ pub fn generate_page(
         let accumulated = accumulate(&res);
         let mut gmi_export = GmiExport {
             accumulated,
+            site: Some(site.clone()),
+            path: old_path.clone(),
             ..Default::default()
         };
         res.traverse(&mut gmi_export);
@@ -449,12 +617,7 @@ pub fn write_org_page(
     links: &HashMap<PathBuf, Vec<Rc<PathBuf>>>,
     _config: Option<&ClamConfig>,
 ) -> Result<(), Error> {
-    let year_ago = std::time::SystemTime::now()
-        .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        .map_err(Error::Clock)?
-        .as_secs()
-        - 365 * 24 * 60 * 60;
-    let year_ago: i64 = year_ago.try_into().map_err(|_| Error::TimeOverflow)?;
+    let year_ago = year_ago()?;
 
     for (
         new_path,
@@ -487,13 +650,7 @@ pub fn write_org_page(
 
         let numdir = old_path.iter().count();
 
-        let notice = if modify_time.seconds() - year_ago < 0 {
-            Some(
-                "this page was last updated over a year ago. facts and circumstances may have changed since.",
-            )
-        } else {
-            None
-        };
+        let notice = stale_notice(modify_time, year_ago);
 
         let incoming: Option<BTreeSet<_>> = links.get(new_path).map(|l| l.iter().collect());
         let incoming: Option<Vec<_>> = incoming.map(|l| {
@@ -551,3 +708,68 @@ pub fn write_redirect_page(path: &Path, target: &str) -> String {
     };
     template.to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::output::gmi::*;
+    use orgize::Org;
+
+    #[test]
+    fn snapshot_gmi_footnotes() {
+        let res = Org::parse(
+            r"i have a footnote[fn:1:beep /boop/ [[https://example.org][link]]][fn:2] and[fn::inline]
+
+[fn:2] and another footnote
+
+more text[fn:2]",
+        );
+        let mut exp = GmiExport::default();
+        res.traverse(&mut exp);
+        assert_eq!(
+            exp.finish(),
+            r"i have a footnote[1][2] and[3]
+
+more text[2]
+
+## footnotes
+
+[1] beep boop link
+[2] and another footnote
+[3] inline
+
+=> https://example.org link
+
+"
+        );
+    }
+
+    #[test]
+    fn snapshot_gmi_toc() {
+        let res = Org::parse(
+            r"#+TOC: headlines 2
+* meow
+see [[https://example.org][webbed sight]]
+** TODO wash the [[https://example.org][fox]]
+*** too deep
+* mrrp",
+        );
+        let mut exp = GmiExport::default();
+        res.traverse(&mut exp);
+        assert_eq!(
+            exp.finish(),
+            r"* meow
+  * TODO wash the fox
+* mrrp
+
+## meow
+see webbed sight
+
+=> https://example.org webbed sight
+
+### TODO wash the fox
+#### too deep
+## mrrp
+"
+        );
+    }
+}
@@ -3,14 +3,18 @@ use crate::{
     config::{ClamConfig, FeedConfig},
     git::HistMeta,
     helpers::org_links,
-    output::{Page, PageMetadata, TokenList, accumulate, get_keywords, infer_title, mangle_link},
+    output::{
+        Page, PageMetadata, Relink, SiteIndex, TocKind, TokenList, accumulate, get_keywords,
+        infer_title, mangle_link, named_captions, number_labels, pages_under, resolve_nref,
+        stale_notice, step_counter, table_name, year_ago,
+    },
 };
 use boilerplate::Trusted;
 use chrono::{DateTime, Datelike};
 use indexmap::IndexMap;
 use orgize::{
-    ParseConfig, SyntaxKind,
-    ast::{Headline, TodoType},
+    ParseConfig, SyntaxKind, SyntaxNode,
+    ast::{Headline, Keyword, Link, TodoType},
     export::{Container, Event, HtmlEscape, HtmlExport, TraversalContext, Traverser},
     rowan::{NodeOrToken, ast::AstNode},
 };
@@ -44,7 +48,14 @@ pub struct Handler {
     pub numdir: usize,
     pub feet: IndexMap<String, (Option<TokenList>, i32)>,
     pub nums: BTreeMap<String, u64>,
+    /// values of the labelled counters in the document
+    pub labels: HashMap<String, u64>,
     pub accumulated: BTreeMap<String, Vec<String>>,
+    /// rewrite links to other pages with this instead of pointing them at html files
+    pub relink: Option<Relink>,
+    pub site: Option<Rc<SiteIndex>>,
+    /// path of the org file being exported
+    pub path: PathBuf,
 }
 
 impl Traverser for Handler {
@@ -79,16 +90,7 @@ r##" <a class=see-focus href="#{id}" aria-label="permalink to section">§</a></h
                 let path = mangle_link(&path, ".html", ".html#");
 
                 if link.is_image() {
-                    if let Some(Some(caption)) = link.caption().map(|c| c.value()) {
-                        self.exp.push_str(format!(
-                            r#"<img src="{}" alt="{}">"#,
-                            HtmlEscape(&path),
-                            HtmlEscape(caption.trim())
-                        ));
-                    } else {
-                        self.exp
-                            .push_str(format!("<img src=\"{}\">", HtmlEscape(&path)));
-                    }
+                    self.output_image(&link, &path);
                     return ctx.skip();
                 }
 
@@ -108,7 +110,14 @@ r##" <a class=see-focus href="#{id}" aria-label="permalink to section">§</a></h
                 self.exp.push_str("</a>");
             }
             Event::Enter(Container::SourceBlock(block)) => {
-                self.exp.push_str("<div class=source>");
+                if let Some(name) = block.name().and_then(|n| n.value()) {
+                    self.exp.push_str(format!(
+                        "<div class=source id=\"{}\">",
+                        HtmlEscape(name.trim())
+                    ));
+                } else {
+                    self.exp.push_str("<div class=source>");
+                }
 
                 if let Some(par) = block.parameters()
                     && let Some(name) = par
@@ -181,6 +190,29 @@ r##" <a class=see-focus href="#{id}" aria-label="permalink to section">§</a></h
                 }
                 ctx.skip();
             }
+            // give named tables something to link to from `#+TOC: tables`
+            Event::Enter(Container::OrgTable(ref table)) => {
+                self.open_table_anchor(table.syntax());
+                self.exp.event(event, ctx);
+            }
+            Event::Enter(Container::Paragraph(ref par)) => {
+                self.open_table_anchor(par.syntax());
+                self.exp.event(event, ctx);
+            }
+            Event::Leave(Container::OrgTable(ref table)) => {
+                let named = table_name(table.syntax().clone()).is_some();
+                self.exp.event(event, ctx);
+                if named {
+                    self.exp.push_str("</div>");
+                }
+            }
+            Event::Leave(Container::Paragraph(ref par)) => {
+                let named = table_name(par.syntax().clone()).is_some();
+                self.exp.event(event, ctx);
+                if named {
+                    self.exp.push_str("</div>");
+                }
+            }
             Event::Enter(Container::VerseBlock(_)) => self.exp.push_str("<pre class=verse>"),
             Event::Enter(Container::FixedWidth(_)) => self.exp.push_str("<pre class=example>"),
             Event::Leave(Container::VerseBlock(_) | Container::FixedWidth(_)) => {
@@ -213,68 +245,23 @@ r##" <a class=see-focus href="#{id}" aria-label="permalink to section">§</a></h
             }
             Event::Enter(Container::Keyword(keyword)) => {
                 if keyword.key().eq_ignore_ascii_case("TOC") {
-                    let value = keyword.value();
-                    let mut value = value.split_ascii_whitespace();
-                    match value.next() {
-                        Some("headlines") | None => (),
-                        Some(o) => {
-                            eprintln!("TOC type {o} not supported");
-                            ctx.skip();
-                            return;
-                        }
-                    }
-                    let limit: usize = if let Some(Ok(val)) = value.next().map(str::parse) {
-                        val
-                    } else {
-                        0
-                    };
-
-                    self.exp
-                        .push_str("<details class=toc><summary>table of contents</summary>");
-
-                    if let Some(Some(parent)) = keyword.syntax().parent().map(|p| p.parent()) {
-                        let mut depth = 0;
-                        for descendant in parent.descendants() {
-                            if let Some(headline) = Headline::cast(descendant) {
-                                let level = headline.level();
-                                if limit != 0 && level > limit {
-                                    continue;
-                                }
-                                if depth == level {
-                                    self.exp.push_str("</li>");
-                                } else {
-                                    while depth < level {
-                                        self.exp.push_str("<ul>");
-                                        depth += 1;
-                                    }
-                                    while depth > level {
-                                        self.exp.push_str("</li></ul>");
-                                        depth -= 1;
-                                    }
-                                }
-
-                                self.exp.push_str(format!(
-                                    "<li><a href=\"#{}\">",
-                                    generate_headline_id(&headline)
-                                ));
-                                self.output_headline_todo(&headline);
-                                for e in headline.title() {
-                                    self.element(e, ctx);
-                                }
-                                self.exp.push_str("</a>");
-                            }
-                        }
-                        while depth > 0 {
-                            self.exp.push_str("</li></ul>");
-                            depth -= 1;
-                        }
-                    }
-
-                    self.exp.push_str("</details>");
+                    self.output_toc(&keyword, ctx);
                 } else if keyword.key().eq_ignore_ascii_case("CUM") {
                     self.exp.push_str("<ul>");
 
-                    if let Some(bucket) = self.accumulated.get(keyword.value().trim()) {
+                    let value = keyword.value();
+                    let bucket = value.trim();
+                    if let Some(site) = self.site.clone()
+                        && let Some(entries) = site.buckets.get(bucket)
+                    {
+                        for entry in entries {
+                            self.exp.push_str(format!(
+                                "<li><a href=\"{}\">{}</a></li>",
+                                HtmlEscape(self.link_target(&entry.link(&self.path))),
+                                HtmlEscape(&entry.name)
+                            ));
+                        }
+                    } else if let Some(bucket) = self.accumulated.get(bucket) {
                         for name in bucket {
                             self.exp.push_str(format!("<li>{}</li>", HtmlEscape(name)));
                         }
@@ -336,6 +323,10 @@ r##"<sup><a id="fnr.{fnum}.{rnum}" href="#fn.{fnum}" role=doc-noteref>[{fnum}]</
                 }
                 ctx.skip();
             }
+            Event::Enter(Container::Document(ref document)) => {
+                self.labels = number_labels(document.syntax());
+                self.exp.event(event, ctx);
+            }
             Event::Leave(Container::Document(_)) => {
                 if !self.feet.is_empty() {
                     self.exp.push_str("<section role=doc-endnotes aria-labelledby=clam.footnotes><h2 id=clam.footnotes>footnotes</h2><ol>");
@@ -374,24 +365,32 @@ r##"<sup><a id="fnr.{fnum}.{rnum}" href="#fn.{fnum}" role=doc-noteref>[{fnum}]</
             Event::Macros(macros) => match macros.key().as_ref() {
                 "n" => {
                     let args = macros.args();
-                    let args: Option<&str> = args.as_deref();
-                    let (name, act) = args
-                        .and_then(|a| a.split_once(','))
-                        .unwrap_or_else(|| (args.unwrap_or(""), ""));
-                    let (name, act) = (name.trim(), act.trim());
-                    let num = if matches!(act, "" | "-") {
-                        self.nums
-                            .get(name)
-                            .copied()
-                            .map(|n| if act == "-" { n } else { n.wrapping_add(1) })
+                    match step_counter(&mut self.nums, args.as_deref()) {
+                        (num, Some(label)) => self
+                            .exp
+                            .push_str(format!("<span id=\"{}\">{num}</span>", HtmlEscape(label))),
+                        (num, None) => self.exp.push_str(format!("{num}")),
+                    }
+                }
+                "nref" => {
+                    let args = macros.args();
+                    if let Some((target, num)) = resolve_nref(
+                        &self.path,
+                        args.as_deref(),
+                        &self.labels,
+                        self.site.as_deref(),
+                    ) {
+                        let target = self.link_target(&target);
+                        self.exp
+                            .push_str(format!("<a href=\"{}\">{num}</a>", HtmlEscape(target)));
                     } else {
-                        act.parse().ok()
+                        eprintln!(
+                            "{}: unknown counter label {}",
+                            self.path.display(),
+                            args.as_deref().unwrap_or("")
+                        );
+                        self.exp.push_str("??");
                     }
-                    .unwrap_or(1);
-
-                    self.exp.push_str(format!("{num}"));
-
-                    self.nums.insert(name.to_string(), num);
                 }
                 "cum" => {
                     if let Some(args) = macros.args()
@@ -432,6 +431,124 @@ impl Handler {
         }
     }
 
+    fn link_target(&self, path: &str) -> String {
+        match &self.relink {
+            Some(relink) => relink(path),
+            None => mangle_link(path, ".html", ".html#"),
+        }
+    }
+
+    fn open_table_anchor(&mut self, node: &SyntaxNode) {
+        if let Some((name, _)) = table_name(node.clone()) {
+            self.exp
+                .push_str(format!("<div id=\"{}\">", HtmlEscape(name)));
+        }
+    }
+
+    fn output_toc(&mut self, keyword: &Keyword, ctx: &mut TraversalContext) {
+        let value = keyword.value();
+        match TocKind::parse(&value) {
+            Some(TocKind::Headlines(limit)) => self.output_headline_toc(keyword, limit, ctx),
+            Some(kind @ (TocKind::Tables | TocKind::Listings)) => {
+                let Some(root) = keyword.syntax().ancestors().last() else {
+                    return;
+                };
+                let listings = matches!(kind, TocKind::Listings);
+                self.exp.push_str(if listings {
+                    "<details class=toc><summary>list of listings</summary><ul>"
+                } else {
+                    "<details class=toc><summary>list of tables</summary><ul>"
+                });
+                for (name, caption) in named_captions(&root, listings) {
+                    self.exp.push_str(format!(
+                        "<li><a href=\"#{}\">{}</a></li>",
+                        HtmlEscape(name),
+                        HtmlEscape(caption)
+                    ));
+                }
+                self.exp.push_str("</ul></details>");
+            }
+            Some(TocKind::Pages(dir)) => {
+                let Some(site) = self.site.clone() else {
+                    return;
+                };
+                self.exp.push_str("<ul>");
+                for (path, title) in pages_under(&site.titles, &self.path, dir)
+                    .into_iter()
+                    .flatten()
+                {
+                    let path = self.link_target(&path);
+                    self.exp.push_str(format!(
+                        "<li><a href=\"{}\">{}</a></li>",
+                        HtmlEscape(path),
+                        HtmlEscape(title)
+                    ));
+                }
+                self.exp.push_str("</ul>");
+            }
+            None => (),
+        }
+    }
+
+    fn output_headline_toc(&mut self, keyword: &Keyword, limit: usize, ctx: &mut TraversalContext) {
+        self.exp
+            .push_str("<details class=toc><summary>table of contents</summary>");
+
+        if let Some(Some(parent)) = keyword.syntax().parent().map(|p| p.parent()) {
+            let mut depth = 0;
+            for descendant in parent.descendants() {
+                if let Some(headline) = Headline::cast(descendant) {
+                    let level = headline.level();
+                    if limit != 0 && level > limit {
+                        continue;
+                    }
+                    if depth >= level {
+                        while depth > level {
+                            self.exp.push_str("</li></ul>");
+                            depth -= 1;
+                        }
+                        self.exp.push_str("</li>");
+                    } else {
+                        while depth < level {
+                            self.exp.push_str("<ul>");
+                            depth += 1;
+                        }
+                    }
+
+                    self.exp.push_str(format!(
+                        "<li><a href=\"#{}\">",
+                        generate_headline_id(&headline)
+                    ));
+                    self.output_headline_todo(&headline);
+                    for e in headline.title() {
+                        self.element(e, ctx);
+                    }
+                    self.exp.push_str("</a>");
+                }
+            }
+            while depth > 0 {
+                self.exp.push_str("</li></ul>");
+                depth -= 1;
+            }
+        }
+
+        self.exp.push_str("</details>");
+    }
+
+    /// output an image with its caption as alt text, if it has one
+    pub fn output_image(&mut self, link: &Link, src: &str) {
+        if let Some(Some(caption)) = link.caption().map(|c| c.value()) {
+            self.exp.push_str(format!(
+                r#"<img src="{}" alt="{}">"#,
+                HtmlEscape(src),
+                HtmlEscape(caption.trim())
+            ));
+        } else {
+            self.exp
+                .push_str(format!("<img src=\"{}\">", HtmlEscape(src)));
+        }
+    }
+
     fn output_headline_todo(&mut self, headline: &Headline) {
         if let Some(keyword) = headline.todo_keyword() {
             self.exp.push_str(match headline.todo_type() {
@@ -447,7 +564,7 @@ impl Handler {
     }
 }
 
-fn generate_headline_id(headline: &Headline) -> String {
+pub fn generate_headline_id(headline: &Headline) -> String {
     let txt: String = headline.title().map(|t| t.to_string()).collect();
 
     if let Some(Some(cid)) = headline.properties().map(|p| p.get("CUSTOM_ID")) {
@@ -464,6 +581,7 @@ pub fn generate_page(
     org_cfg: &ParseConfig,
     pages: &mut HashMap<PathBuf, Page>,
     links: &mut HashMap<PathBuf, Vec<Rc<PathBuf>>>,
+    site: &Rc<SiteIndex>,
 ) -> Result<(), Error> {
     let mut full_path: PathBuf = format!("{dir}{name}").into();
     if full_path
@@ -495,6 +613,8 @@ pub fn generate_page(
         let mut html_export = Handler {
             numdir: old_path.iter().count(),
             accumulated,
+            site: Some(site.clone()),
+            path: old_path.clone(),
             ..Default::default()
         };
         res.traverse(&mut html_export);
@@ -522,12 +642,7 @@ pub fn write_org_page(
     links: &HashMap<PathBuf, Vec<Rc<PathBuf>>>,
     config: Option<&ClamConfig>,
 ) -> Result<(), Error> {
-    let year_ago = std::time::SystemTime::now()
-        .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        .map_err(Error::Clock)?
-        .as_secs()
-        - 365 * 24 * 60 * 60;
-    let year_ago: i64 = year_ago.try_into().map_err(|_| Error::TimeOverflow)?;
+    let year_ago = year_ago()?;
 
     let (header, footer, nav, inline) = config.map_or((None, None, false, false), |conf| {
         (
@@ -570,13 +685,7 @@ pub fn write_org_page(
 
         let numdir = old_path.iter().count();
 
-        let notice = if modify_time.seconds() - year_ago < 0 {
-            Some(
-                "this page was last updated over a year ago. facts and circumstances may have changed since.",
-            )
-        } else {
-            None
-        };
+        let notice = stale_notice(modify_time, year_ago);
 
         let incoming: Option<BTreeSet<_>> = links.get(new_path).map(|l| l.iter().collect());
         let incoming: Option<Vec<_>> = incoming.map(|l| {
@@ -659,7 +768,7 @@ pub fn write_redirect_page(path: &Path, target: &str) -> String {
 
 #[cfg(test)]
 mod tests {
-    use crate::output::html::*;
+    use crate::output::{PageTitles, html::*};
     use orgize::Org;
 
     #[test]
@@ -717,4 +826,65 @@ AAAA even more
 </li></ol></section></main>"##
         );
     }
+
+    #[test]
+    fn snapshot_html_toc_lists() {
+        let res = Org::parse(
+            r"#+TOC: tables
+#+TOC: listings
+#+TOC: pages notes
+
+#+NAME: meow
+#+CAPTION: the fox says
+#+begin_src sh
+echo meow
+#+end_src",
+        );
+        let titles = PageTitles::from([
+            ("notes/a.org".into(), "first note".to_string()),
+            ("notes/deeper/b.org".into(), "second note".to_string()),
+            ("other.org".into(), "not a note".to_string()),
+        ]);
+        let mut exp = Handler {
+            site: Some(Rc::new(SiteIndex {
+                titles,
+                ..Default::default()
+            })),
+            path: "index.org".into(),
+            ..Default::default()
+        };
+        res.traverse(&mut exp);
+        assert_eq!(
+            exp.exp.finish(),
+            r##"<main><section><details class=toc><summary>list of tables</summary><ul></ul></details><details class=toc><summary>list of listings</summary><ul><li><a href="#meow">the fox says</a></li></ul></details><ul><li><a href="notes/a.html">first note</a></li><li><a href="notes/deeper/b.html">second note</a></li></ul><div class=source id="meow"><pre><code class="language-sh">echo meow
+</code></pre></div></section></main>"##
+        );
+    }
+
+    #[test]
+    fn snapshot_html_nref() {
+        let res = Org::parse(
+            "see figure {{{nref(cat)}}} and {{{nref(dog, ../pets.org)}}}
+
+{{{n(fig)}}} {{{n(fig, -, cat)}}} {{{nref(nope)}}}",
+        );
+        let labels = HashMap::from([(
+            PathBuf::from("pets.org"),
+            HashMap::from([("dog".to_string(), 3)]),
+        )]);
+        let mut exp = Handler {
+            site: Some(Rc::new(SiteIndex {
+                labels,
+                ..Default::default()
+            })),
+            path: "notes/a.org".into(),
+            ..Default::default()
+        };
+        res.traverse(&mut exp);
+        assert_eq!(
+            exp.exp.finish(),
+            r##"<main><section><p>see figure <a href="#cat">1</a> and <a href="../pets.html#dog">3</a>
+</p><p>1 <span id="cat">1</span> ??</p></section></main>"##
+        );
+    }
 }
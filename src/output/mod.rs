@@ -1,8 +1,16 @@
-use crate::{Error, OutputFormat, config::ClamConfig, git::HistMeta};
+use crate::{
+    Error, OutputFormat,
+    config::ClamConfig,
+    git::{self, HistMeta},
+    helpers::{relative_link, resolve_relative},
+    include::{IncludeMap, expand_includes},
+    macros::{MacroMap, expand_macros},
+};
 use chrono::NaiveDateTime;
+use git2::{Repository, Time, Tree};
 use orgize::{
-    Org, ParseConfig, SyntaxNode, SyntaxToken,
-    ast::Macros,
+    Org, ParseConfig, SyntaxKind, SyntaxNode, SyntaxToken,
+    ast::{Headline, Macros, OrgTable, Paragraph, SourceBlock, filter_token},
     rowan::{NodeOrToken, ast::AstNode},
 };
 use slugify::slugify;
@@ -21,6 +29,12 @@ pub mod html;
 
 pub type TokenList = Vec<NodeOrToken<SyntaxNode, SyntaxToken>>;
 
+/// rewrite link targets with this instead of pointing them at other output files
+pub type Relink = Box<dyn Fn(&str) -> String>;
+
+/// titles of every org page in the site, keyed by the path of the org file
+pub type PageTitles = BTreeMap<PathBuf, String>;
+
 pub struct Page {
     pub title: String,
     pub old_path: PathBuf,
@@ -93,6 +107,333 @@ pub fn accumulate(res: &Org) -> BTreeMap<String, Vec<String>> {
     out
 }
 
+/// what a `#+TOC:` keyword asks to be listed
+pub enum TocKind<'a> {
+    /// headlines, up to a depth if nonzero
+    Headlines(usize),
+    Tables,
+    Listings,
+    /// pages below a directory, relative to the current page
+    Pages(&'a str),
+}
+
+impl<'a> TocKind<'a> {
+    pub fn parse(value: &'a str) -> Option<Self> {
+        let mut value = value.split_ascii_whitespace();
+        Some(match value.next() {
+            Some("headlines") | None => {
+                let limit = if let Some(Ok(val)) = value.next().map(str::parse) {
+                    val
+                } else {
+                    0
+                };
+                Self::Headlines(limit)
+            }
+            Some("tables") => Self::Tables,
+            Some("listings") => Self::Listings,
+            Some("pages") => Self::Pages(value.next().unwrap_or(".")),
+            Some(o) => {
+                eprintln!("TOC type {o} not supported");
+                return None;
+            }
+        })
+    }
+}
+
+/// name and caption of a table, if it is named
+///
+/// FIXME: orgize currently parses tables with affiliated keywords as paragraphs, so
+/// paragraphs that look like tables count too until that gets fixed
+pub fn table_name(node: SyntaxNode) -> Option<(String, Option<String>)> {
+    let (name, caption) = if let Some(table) = OrgTable::cast(node.clone()) {
+        (table.name(), table.caption())
+    } else {
+        let par = Paragraph::cast(node)?;
+        let text = par
+            .syntax()
+            .children_with_tokens()
+            .find_map(filter_token(SyntaxKind::TEXT))?;
+        if !text.trim_start().starts_with('|') {
+            return None;
+        }
+        (par.name(), par.caption())
+    };
+    Some((
+        name?.value()?.trim().to_string(),
+        caption
+            .and_then(|c| c.value())
+            .map(|c| c.trim().to_string()),
+    ))
+}
+
+/// names and captions of the tables (or source blocks, if `listings`) that have both
+pub fn named_captions(root: &SyntaxNode, listings: bool) -> Vec<(String, String)> {
+    root.descendants()
+        .filter_map(|node| {
+            if listings {
+                let block = SourceBlock::cast(node)?;
+                Some((
+                    block.name()?.value()?.trim().to_string(),
+                    block.caption()?.value()?.trim().to_string(),
+                ))
+            } else {
+                let (name, caption) = table_name(node)?;
+                Some((name, caption?))
+            }
+        })
+        .collect()
+}
+
+/// a `{{{cum(bucket, name)}}}` entry, for buckets gathered from every page
+pub struct CumEntry {
+    pub name: String,
+    /// path of the org file it is in
+    pub page: PathBuf,
+    /// id of the headline it is under
+    pub headline: Option<String>,
+}
+
+impl CumEntry {
+    /// link to where the entry is from the page at `from`
+    pub fn link(&self, from: &Path) -> String {
+        let mut link = relative_link(from, &self.page);
+        if let Some(id) = &self.headline {
+            link.push('#');
+            link.push_str(id);
+        }
+        link
+    }
+}
+
+/// things gathered from every page before any of them are exported
+#[derive(Default)]
+pub struct SiteIndex {
+    pub titles: PageTitles,
+    /// entries of the cum buckets shared across the whole site
+    pub buckets: BTreeMap<String, Vec<CumEntry>>,
+    /// labelled `n` counters of every page
+    pub labels: HashMap<PathBuf, HashMap<String, u64>>,
+}
+
+impl SiteIndex {
+    fn add_page(&mut self, path: PathBuf, res: &Org) {
+        for descendant in res.document().syntax().descendants() {
+            if let Some(macros) = Macros::cast(descendant)
+                && macros.key() == "cum"
+                && let Some(args) = macros.args()
+                && let Some((bucket, name)) = args.split_once(',')
+                && let Some(entries) = self.buckets.get_mut(bucket.trim())
+            {
+                entries.push(CumEntry {
+                    name: name.trim().to_string(),
+                    page: path.clone(),
+                    headline: macros
+                        .syntax()
+                        .ancestors()
+                        .find_map(Headline::cast)
+                        .map(|h| html::generate_headline_id(&h)),
+                });
+            }
+        }
+
+        let labels = number_labels(res.document().syntax());
+        if !labels.is_empty() {
+            self.labels.insert(path.clone(), labels);
+        }
+
+        let title = res.title().unwrap_or_else(|| infer_title(&path));
+        self.titles.insert(path, title);
+    }
+}
+
+/// whether any org page or site macro mentions `nref` or `TOC`, which need the [`SiteIndex`]
+///
+/// only looks at the raw text, which is a lot cheaper than expanding and parsing every page
+fn wants_index(repo: &Repository, tree: &Tree, site_macros: &MacroMap) -> bool {
+    let mentions = |text: &[u8]| {
+        text.windows(4)
+            .any(|w| w.eq_ignore_ascii_case(b"nref") || w.eq_ignore_ascii_case(b"toc:"))
+    };
+    if site_macros.values().any(|m| mentions(m.as_bytes())) {
+        return true;
+    }
+    let mut found = false;
+    _ = tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+        if entry.kind() == Some(git2::ObjectType::Blob)
+            && entry
+                .name()
+                .ok()
+                .and_then(|n| Path::new(n).extension())
+                .is_some_and(|e| e.eq_ignore_ascii_case("org"))
+            && let Ok(blob) = repo.find_blob(entry.id())
+            && mentions(blob.content())
+        {
+            found = true;
+            return git2::TreeWalkResult::Abort;
+        }
+        git2::TreeWalkResult::Ok
+    });
+    found
+}
+
+/// gather titles and the entries of the shared cum `buckets` from every org page
+///
+/// pages get their includes and macros expanded first, so they are indexed as they get exported
+pub fn site_index(
+    repo: &Repository,
+    tree: &Tree,
+    org_cfg: &ParseConfig,
+    buckets: &[String],
+    site_macros: &MacroMap,
+) -> Result<SiteIndex, Error> {
+    if buckets.is_empty() && !wants_index(repo, tree, site_macros) {
+        return Ok(SiteIndex::default());
+    }
+    let mut index = SiteIndex {
+        buckets: buckets.iter().map(|b| (b.clone(), vec![])).collect(),
+        ..Default::default()
+    };
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        // anything going wrong here will be complained about when generating the page
+        _ = git::walk_callback(repo, dir, entry, false, |name, blob| {
+            let path = PathBuf::from(format!("{dir}{name}"));
+            if path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("org"))
+            {
+                // which pages include what only matters for their history
+                let file = expand_includes(
+                    repo,
+                    tree,
+                    org_cfg,
+                    &path,
+                    blob.content(),
+                    &mut IncludeMap::new(),
+                );
+                let file = expand_macros(&path, &file, site_macros, org_cfg);
+                let fstr = std::str::from_utf8(&file).map_err(Error::NonUTF8Org)?;
+                index.add_page(path, &org_cfg.clone().parse(fstr));
+            }
+            Ok(())
+        });
+        0
+    })?;
+    Ok(index)
+}
+
+/// pages below `dir` for `#+TOC: pages`, as paths relative to `page` along with their titles
+///
+/// gives nothing back if `dir` tries to escape the directory `page` is in
+pub fn pages_under<'a>(
+    titles: &'a PageTitles,
+    page: &Path,
+    dir: &str,
+) -> Option<Vec<(String, &'a str)>> {
+    let here = page.parent().unwrap_or_else(|| Path::new(""));
+    let mut base = here.to_path_buf();
+    for component in Path::new(dir).components() {
+        match component {
+            Component::Normal(c) => base.push(c),
+            Component::CurDir => (),
+            _ => {
+                eprintln!("TOC pages {dir} is not below {}", page.display());
+                return None;
+            }
+        }
+    }
+    Some(
+        titles
+            .iter()
+            .filter(|(p, _)| p.starts_with(&base) && *p != page)
+            .filter_map(|(p, title)| {
+                Some((
+                    p.strip_prefix(here).ok()?.to_string_lossy().into_owned(),
+                    title.as_str(),
+                ))
+            })
+            .collect(),
+    )
+}
+
+/// step the counter an `{{{n(name, action, label)}}}` macro refers to, giving back its new
+/// value and the label it got, if any
+pub fn step_counter<'a>(
+    nums: &mut BTreeMap<String, u64>,
+    args: Option<&'a str>,
+) -> (u64, Option<&'a str>) {
+    let mut args = args.unwrap_or("").splitn(3, ',').map(str::trim);
+    let name = args.next().unwrap_or("");
+    let act = args.next().unwrap_or("");
+    let label = args.next().filter(|l| !l.is_empty());
+    let num = if matches!(act, "" | "-") {
+        nums.get(name)
+            .copied()
+            .map(|n| if act == "-" { n } else { n.wrapping_add(1) })
+    } else {
+        act.parse().ok()
+    }
+    .unwrap_or(1);
+
+    nums.insert(name.to_string(), num);
+    (num, label)
+}
+
+/// values of every labelled `n` counter in a document, so they can be referenced before
+/// getting to them
+pub fn number_labels(root: &SyntaxNode) -> HashMap<String, u64> {
+    let mut nums = BTreeMap::new();
+    let mut labels = HashMap::new();
+    for macros in root.descendants().filter_map(Macros::cast) {
+        if macros.key() == "n" {
+            let args = macros.args();
+            if let (num, Some(label)) = step_counter(&mut nums, args.as_deref()) {
+                labels.insert(label.to_string(), num);
+            }
+        }
+    }
+    labels
+}
+
+/// where an `{{{nref(label, page)}}}` macro points, and the number it should show
+///
+/// the page is relative to `path`, and defaults to it
+pub fn resolve_nref(
+    path: &Path,
+    args: Option<&str>,
+    labels: &HashMap<String, u64>,
+    site: Option<&SiteIndex>,
+) -> Option<(String, u64)> {
+    let args = args?;
+    let (label, page) = args.split_once(',').unwrap_or((args, ""));
+    let (label, page) = (label.trim(), page.trim());
+    if page.is_empty() {
+        return Some((format!("#{label}"), *labels.get(label)?));
+    }
+    let target = resolve_relative(path, page)?;
+    let num = site?.labels.get(&target)?.get(label)?;
+    Some((format!("{page}#{label}"), *num))
+}
+
+/// unix timestamp of a year ago, pages last modified before it are considered stale
+pub fn year_ago() -> Result<i64, Error> {
+    let year_ago = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map_err(Error::Clock)?
+        .as_secs()
+        - 365 * 24 * 60 * 60;
+    year_ago.try_into().map_err(|_| Error::TimeOverflow)
+}
+
+pub fn stale_notice(modify_time: &Time, year_ago: i64) -> Option<&'static str> {
+    if modify_time.seconds() - year_ago < 0 {
+        Some(
+            "this page was last updated over a year ago. facts and circumstances may have changed since.",
+        )
+    } else {
+        None
+    }
+}
+
 pub fn write_org_page(
     format: OutputFormat,
     pages: &HashMap<PathBuf, Page>,
@@ -106,6 +447,7 @@ pub fn write_org_page(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn generate_page(
     format: OutputFormat,
     dir: &str,
@@ -114,10 +456,11 @@ pub fn generate_page(
     org_cfg: &ParseConfig,
     pages: &mut HashMap<PathBuf, Page>,
     links: &mut HashMap<PathBuf, Vec<Rc<PathBuf>>>,
+    site: &Rc<SiteIndex>,
 ) -> Result<(), Error> {
     match format {
-        OutputFormat::Html => html::generate_page(dir, name, file, org_cfg, pages, links),
-        OutputFormat::Gmi => gmi::generate_page(dir, name, file, org_cfg, pages, links),
+        OutputFormat::Html => html::generate_page(dir, name, file, org_cfg, pages, links, site),
+        OutputFormat::Gmi => gmi::generate_page(dir, name, file, org_cfg, pages, links, site),
     }
 }
 
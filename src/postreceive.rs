@@ -0,0 +1,71 @@
+use crate::{
+    Error, PostReceiveArgs,
+    config::{self, OverrideConfig},
+    deploy,
+};
+use git2::{Oid, Repository};
+use std::io;
+
+/// full name of the branch to build, following HEAD in bare repositories
+fn branch_ref(repo: &Repository, branch: &str) -> Result<String, Error> {
+    if branch == "HEAD" {
+        let head = repo.find_reference("HEAD")?;
+        return head
+            .symbolic_target()?
+            .map(str::to_string)
+            .ok_or(Error::UnknownRef);
+    }
+    if branch.starts_with("refs/") {
+        Ok(branch.to_string())
+    } else {
+        Ok(format!("refs/heads/{branch}"))
+    }
+}
+
+fn handle(args: &PostReceiveArgs) -> Result<(), Error> {
+    let repo = Repository::open_from_env()?;
+    let branch = branch_ref(&repo, &args.branch)?;
+
+    let mut updated = false;
+    for line in io::stdin().lines() {
+        let line = line.map_err(Error::Stdin)?;
+        let split: Vec<_> = line.split(' ').collect();
+        let [_, new, refname] = split[..] else {
+            return Err(Error::InvalidHookInput);
+        };
+        if refname == branch && !Oid::from_str(new)?.is_zero() {
+            updated = true;
+        }
+    }
+    if !updated {
+        return Ok(());
+    }
+
+    let overrides = OverrideConfig {
+        url: args.url.clone(),
+        inline: args.inline,
+        format: args.format,
+    };
+    // read before building, as that happens in a fresh directory
+    let config = config::read_config(&overrides);
+    deploy::with_lock(&args.out, || {
+        // another push may have come in while waiting, so build whatever is newest
+        let commit = repo.find_reference(&branch)?.peel_to_commit()?;
+        println!("building {} into {}", commit.id(), args.out.display());
+        deploy::build_into(
+            &repo,
+            &commit,
+            &overrides,
+            config.as_ref(),
+            &args.out,
+            args.keep,
+        )
+    })
+}
+
+pub fn hook(args: &PostReceiveArgs) {
+    if let Err(e) = handle(args) {
+        println!("failed to build: {e}");
+        std::process::exit(1);
+    }
+}
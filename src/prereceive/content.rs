@@ -0,0 +1,213 @@
+use super::{Report, Rules};
+use crate::{Error, default_org_cfg, helpers::org_links};
+use git2::{Delta, DiffDelta, FileMode, Repository, Tree};
+use orgize::{Org, ParseConfig};
+use slugify::slugify;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+fn is_org(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("org"))
+}
+
+/// pages an org document links to that are not in `tree`
+fn redlinks(res: &Org, path: &Path, tree: &Tree) -> BTreeSet<PathBuf> {
+    let mut missing = BTreeSet::new();
+    org_links(res, path, |l| {
+        if is_org(l) && tree.get_path(l).is_err() {
+            missing.insert(l.to_owned());
+        }
+    });
+    missing
+}
+
+/// run a function on every org file in a tree that is valid utf-8
+fn walk_org<F>(repo: &Repository, tree: &Tree, mut callback: F) -> Result<(), Error>
+where
+    F: FnMut(PathBuf, &str),
+{
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        let Ok(name) = entry.name() else {
+            return 0;
+        };
+        let path = PathBuf::from(format!("{dir}{name}"));
+        if is_org(&path)
+            && let Ok(blob) = repo.find_blob(entry.id())
+            && let Ok(text) = std::str::from_utf8(blob.content())
+        {
+            callback(path, text);
+        }
+        0
+    })?;
+    Ok(())
+}
+
+/// what the checks need to know about the rest of the tree, worked out only when needed
+struct Context<'a> {
+    repo: &'a Repository,
+    tree: &'a Tree<'a>,
+    org_cfg: ParseConfig,
+    slugs: Option<HashMap<String, Vec<PathBuf>>>,
+}
+
+impl Context<'_> {
+    fn check_slug(&mut self, path: &Path) -> Result<(), Error> {
+        if self.slugs.is_none() {
+            let mut slugs: HashMap<String, Vec<PathBuf>> = HashMap::new();
+            self.tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+                if let Ok(name) = entry.name()
+                    && entry.kind() == Some(git2::ObjectType::Blob)
+                {
+                    let path = format!("{dir}{name}");
+                    slugs.entry(slugify!(&path)).or_default().push(path.into());
+                }
+                0
+            })?;
+            self.slugs = Some(slugs);
+        }
+
+        let slug = slugify!(&path.to_string_lossy());
+        if self
+            .slugs
+            .as_ref()
+            .and_then(|s| s.get(&slug))
+            .is_some_and(|paths| paths.iter().any(|p| p != path))
+        {
+            return Err(Error::SlugCollision(path.display().to_string()));
+        }
+        Ok(())
+    }
+
+    /// check an added or modified org page
+    fn check_page(
+        &self,
+        rules: &Rules,
+        change: &DiffDelta,
+        path: &Path,
+        old_tree: Option<&Tree>,
+        report: &mut Report,
+    ) -> Result<(), Error> {
+        let blob = self.repo.find_blob(change.new_file().id())?;
+        let Ok(text) = std::str::from_utf8(blob.content()) else {
+            if rules.require_utf8 {
+                report.add(Error::BadUtf8Page(path.display().to_string()));
+            }
+            return Ok(());
+        };
+        let res = self.org_cfg.clone().parse(text);
+
+        if rules.require_title && res.title().is_none() {
+            report.add(Error::MissingTitle(path.display().to_string()));
+        }
+
+        if rules.no_new_redlinks {
+            let mut links = redlinks(&res, path, self.tree);
+            // links that were already broken are not this push's fault, even if the page moved
+            if matches!(
+                change.status(),
+                Delta::Modified | Delta::Renamed | Delta::Copied
+            ) && let Some(old_tree) = old_tree
+                && let Some(old_path) = change.old_file().path()
+                && let Ok(old) = self.repo.find_blob(change.old_file().id())
+                && let Ok(old) = std::str::from_utf8(old.content())
+            {
+                let old = self.org_cfg.clone().parse(old);
+                for link in redlinks(&old, old_path, old_tree) {
+                    links.remove(&link);
+                }
+            }
+            for link in links {
+                report.add(Error::NewRedlink(format!(
+                    "{} -> {}",
+                    path.display(),
+                    link.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// make sure nothing links to pages that got deleted
+    fn check_deleted(&self, deleted: &BTreeSet<PathBuf>, report: &mut Report) -> Result<(), Error> {
+        walk_org(self.repo, self.tree, |path, text| {
+            let res = self.org_cfg.clone().parse(text);
+            org_links(&res, &path, |l| {
+                if deleted.contains(l) {
+                    report.add(Error::NewRedlink(format!(
+                        "{} -> {}",
+                        path.display(),
+                        l.display()
+                    )));
+                }
+            });
+        })
+    }
+}
+
+/// read the pages a diff touches and check them with the content rules
+pub fn check_content(
+    repo: &Repository,
+    rules: &Rules,
+    tree: &Tree,
+    old_tree: Option<&Tree>,
+    diff: &git2::Diff<'_>,
+    report: &mut Report,
+) -> Result<(), Error> {
+    if !(rules.require_utf8 || rules.no_new_redlinks || rules.require_title || rules.unique_slugs) {
+        return Ok(());
+    }
+
+    let mut ctx = Context {
+        repo,
+        tree,
+        org_cfg: default_org_cfg(),
+        slugs: None,
+    };
+    let mut deleted = BTreeSet::new();
+
+    for change in diff.deltas() {
+        // check_deltas already reported paths that are not utf-8
+        let (Some(old), Some(path)) = (change.old_file().path(), change.new_file().path()) else {
+            continue;
+        };
+        let is_page = matches!(
+            change.new_file().mode(),
+            FileMode::Blob | FileMode::BlobExecutable
+        );
+        let created = match change.status() {
+            Delta::Added | Delta::Copied => true,
+            Delta::Modified => false,
+            // pages that turned into symlinks or submodules are as good as gone
+            Delta::Typechange if is_page => false,
+            Delta::Deleted | Delta::Typechange => {
+                if is_org(old) {
+                    deleted.insert(old.to_owned());
+                }
+                continue;
+            }
+            Delta::Renamed => {
+                if is_org(old) {
+                    deleted.insert(old.to_owned());
+                }
+                true
+            }
+            _ => continue,
+        };
+        if rules.unique_slugs && created {
+            report.check(ctx.check_slug(path));
+        }
+        if is_org(path) && is_page {
+            ctx.check_page(rules, &change, path, old_tree, report)?;
+        }
+    }
+
+    if rules.no_new_redlinks && !deleted.is_empty() {
+        ctx.check_deleted(&deleted, report)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use super::Rules;
+use crate::Error;
+use git2::{ObjectType, Oid, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use std::{collections::HashSet, path::Path};
+
+/// new blobs seen so far in a push, to limit its total size
+#[derive(Debug, Default)]
+pub struct Pushed {
+    /// commits the refs pointed at before the push
+    tips: Vec<Oid>,
+    blobs: HashSet<Oid>,
+    /// blobs and trees in existing refs or in commits the push builds on
+    known: HashSet<Oid>,
+    /// whether what the tips have is in `known` yet
+    tips_known: bool,
+    size: u64,
+}
+
+impl Pushed {
+    pub fn new(tips: Vec<Oid>) -> Self {
+        Self {
+            tips,
+            ..Default::default()
+        }
+    }
+
+    pub fn tips(&self) -> &[Oid] {
+        &self.tips
+    }
+
+    /// remember what is in the trees of the existing refs, once
+    fn know_tips(&mut self, repo: &Repository) -> Result<(), Error> {
+        if self.tips_known {
+            return Ok(());
+        }
+        for tip in self.tips.clone() {
+            self.know_tree(&repo.find_commit(tip)?.tree()?)?;
+        }
+        self.tips_known = true;
+        Ok(())
+    }
+
+    /// remember what is in a tree the push builds on, so renaming or merging files that were
+    /// already there does not count them as new
+    pub fn know_tree(&mut self, tree: &Tree) -> Result<(), Error> {
+        if !self.known.insert(tree.id()) {
+            return Ok(());
+        }
+        tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+            let seen = !self.known.insert(entry.id());
+            if seen && entry.kind() == Some(ObjectType::Tree) {
+                TreeWalkResult::Skip
+            } else {
+                TreeWalkResult::Ok
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// how much of a file to look at when guessing its type
+const SNIFF_LEN: usize = 1024;
+
+/// whether text starts with an svg element, after any xml prolog, doctype and comments
+fn is_svg(mut text: &str) -> bool {
+    loop {
+        text = text.trim_start();
+        let end = if text.starts_with("<?") {
+            "?>"
+        } else if text.starts_with("<!--") {
+            "-->"
+        } else if text.starts_with("<!") {
+            ">"
+        } else {
+            break;
+        };
+        match text.find(end) {
+            Some(i) => text = &text[i + end.len()..],
+            None => return false,
+        }
+    }
+    text.strip_prefix("<svg")
+        .and_then(|rest| rest.chars().next())
+        .is_some_and(|c| c == '>' || c.is_ascii_whitespace())
+}
+
+/// guess the mime type of a file from its first few bytes
+fn sniff_mime(content: &[u8]) -> &'static str {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\xfd7zXZ\0", "application/x-xz"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"\x7fELF", "application/x-executable"),
+        (b"MZ", "application/vnd.microsoft.portable-executable"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+
+    if let Some((_, mime)) = MAGIC.iter().find(|(m, _)| content.starts_with(m)) {
+        return mime;
+    }
+    match content.get(..12) {
+        Some([b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P']) => return "image/webp",
+        Some([_, _, _, _, b'f', b't', b'y', b'p', ..]) => return "video/mp4",
+        _ => (),
+    }
+    let head = &content[..content.len().min(SNIFF_LEN)];
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // a character cut in half at the end of the head
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return "application/octet-stream",
+    };
+    if is_svg(text) {
+        "image/svg+xml"
+    } else if text.contains('\0') {
+        "application/octet-stream"
+    } else {
+        "text/plain"
+    }
+}
+
+/// match a mime type against something like `image/png` or `image/*`
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => mime.split('/').next() == Some(kind),
+        None => pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+fn has_extension(list: &[String], ext: &str) -> bool {
+    list.iter()
+        .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(ext))
+}
+
+/// check the size and type of a new blob
+pub fn check_file(
+    repo: &Repository,
+    rules: &Rules,
+    path: &str,
+    oid: Oid,
+    pushed: &mut Pushed,
+) -> Result<(), Error> {
+    let ext = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy())
+        .unwrap_or_default();
+    if (!rules.allow_extension.is_empty() && !has_extension(&rules.allow_extension, &ext))
+        || has_extension(&rules.deny_extension, &ext)
+    {
+        return Err(Error::BadFileType(path.to_string()));
+    }
+
+    let checks_blob = rules.max_blob_size.is_some()
+        || rules.max_push_size.is_some()
+        || !rules.allow_mime.is_empty()
+        || !rules.deny_mime.is_empty();
+    if !checks_blob {
+        return Ok(());
+    }
+    // blobs that existing refs or the parents of a pushed commit have are not new, and new ones
+    // count once, no matter how many paths they show up at
+    pushed.know_tips(repo)?;
+    if pushed.known.contains(&oid) || !pushed.blobs.insert(oid) {
+        return Ok(());
+    }
+
+    let (size, _) = repo.odb()?.read_header(oid)?;
+    let size = u64::try_from(size).unwrap_or(u64::MAX);
+    if rules.max_blob_size.is_some_and(|max| size > max) {
+        return Err(Error::BlobTooBig(format!("{path} is {size} bytes")));
+    }
+    let before = pushed.size;
+    pushed.size = pushed.size.saturating_add(size);
+    // only complain once, when the push first goes over
+    if rules
+        .max_push_size
+        .is_some_and(|max| pushed.size > max && before <= max)
+    {
+        return Err(Error::PushTooBig(format!(
+            "{} bytes by the time of {path}",
+            pushed.size
+        )));
+    }
+
+    if !rules.allow_mime.is_empty() || !rules.deny_mime.is_empty() {
+        let blob = repo.find_blob(oid)?;
+        let mime = sniff_mime(blob.content());
+        if (!rules.allow_mime.is_empty() && !rules.allow_mime.iter().any(|m| mime_matches(m, mime)))
+            || rules.deny_mime.iter().any(|m| mime_matches(m, mime))
+        {
+            return Err(Error::BadFileType(format!("{path} ({mime})")));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prereceive::files::*;
+
+    #[test]
+    fn check_sniff_mime() {
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n...."), "image/png");
+        assert_eq!(sniff_mime(b"\x7fELF\x02\x01"), "application/x-executable");
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff_mime(b"#+TITLE: meow\n"), "text/plain");
+        assert_eq!(sniff_mime(b"\0\x01\x02"), "application/octet-stream");
+        assert_eq!(
+            sniff_mime(b"<?xml version=\"1.0\"?>\n<!-- cat -->\n<!DOCTYPE svg>\n<svg xmlns=\"\">"),
+            "image/svg+xml"
+        );
+        assert_eq!(sniff_mime(b"<svg>"), "image/svg+xml");
+        assert_eq!(
+            sniff_mime(b"#+BEGIN_EXPORT html\n<svg width=\"1\">"),
+            "text/plain"
+        );
+        assert_eq!(sniff_mime(b"<svgs>"), "text/plain");
+        assert!(mime_matches("image/*", "image/png"));
+        assert!(!mime_matches("image/*", "text/plain"));
+    }
+}
@@ -0,0 +1,966 @@
+use crate::{
+    CheckPushArgs, Error, PreReceiveArgs,
+    helpers::{de_regex_set, full_regex_set},
+    signers::{AllowedSigners, fingerprint, signing_key},
+};
+use git2::{
+    Commit, Delta, DiffFile, DiffFindOptions, DiffOptions, ObjectFormat, Oid, Repository, Tree,
+};
+use regex::RegexSet;
+use serde::Deserialize;
+use std::{collections::HashSet, io};
+
+mod content;
+mod files;
+mod report;
+
+use content::check_content;
+use files::{Pushed, check_file};
+use report::Report;
+
+/// match a pattern like `refs/heads/draft/*`, where `*` matches anything
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let Some(last) = parts.next_back() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        let Some(i) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[i + part.len()..];
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[derive(Debug)]
+pub enum Action {
+    Create,
+    Delete,
+    Modify,
+    /// a file became a symlink or submodule, or the other way around
+    TypeChange,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Rules {
+    #[serde(default)]
+    require_signing: bool,
+    #[serde(default)]
+    no_deletion: bool,
+    #[serde(default)]
+    no_creation: bool,
+    #[serde(default)]
+    no_typechange: bool,
+    #[serde(default)]
+    no_merges: bool,
+    #[serde(default = "full_regex_set", deserialize_with = "de_regex_set")]
+    allow_pattern: RegexSet,
+    #[serde(default = "RegexSet::empty", deserialize_with = "de_regex_set")]
+    protect_pattern: RegexSet,
+    #[serde(default)]
+    require_utf8: bool,
+    #[serde(default)]
+    no_new_redlinks: bool,
+    #[serde(default)]
+    require_title: bool,
+    #[serde(default)]
+    unique_slugs: bool,
+    max_blob_size: Option<u64>,
+    max_push_size: Option<u64>,
+    #[serde(default)]
+    allow_extension: Vec<String>,
+    #[serde(default)]
+    deny_extension: Vec<String>,
+    #[serde(default)]
+    allow_mime: Vec<String>,
+    #[serde(default)]
+    deny_mime: Vec<String>,
+    #[serde(default)]
+    allow_create_ref: Vec<String>,
+    #[serde(default)]
+    allow_delete_ref: Vec<String>,
+}
+
+impl Rules {
+    pub fn from_args(args: &PreReceiveArgs) -> Result<Self, Error> {
+        let allow_pattern = RegexSet::new(&args.allow_pattern)?;
+        let protect_pattern = RegexSet::new(&args.protect_pattern)?;
+        let res = Self {
+            require_signing: args.require_signing,
+            no_deletion: args.no_deletion,
+            no_creation: args.no_creation,
+            no_typechange: args.no_typechange,
+            no_merges: args.no_merges,
+            allow_pattern,
+            protect_pattern,
+            require_utf8: args.require_utf8,
+            no_new_redlinks: args.no_new_redlinks,
+            require_title: args.require_title,
+            unique_slugs: args.unique_slugs,
+            max_blob_size: args.max_blob_size,
+            max_push_size: args.max_push_size,
+            allow_extension: args.allow_extension.clone(),
+            deny_extension: args.deny_extension.clone(),
+            allow_mime: args.allow_mime.clone(),
+            deny_mime: args.deny_mime.clone(),
+            allow_create_ref: args.allow_create_ref.clone(),
+            allow_delete_ref: args.allow_delete_ref.clone(),
+        };
+        Ok(res)
+    }
+
+    pub fn signed(&self, is_signed: bool) -> Result<(), Error> {
+        if self.require_signing && !is_signed {
+            return Err(Error::NotSigned);
+        }
+        Ok(())
+    }
+
+    pub fn parents(&self, parent_count: usize) -> Result<(), Error> {
+        if self.no_merges && parent_count > 1 {
+            return Err(Error::Merge);
+        }
+        Ok(())
+    }
+
+    pub fn check_ref(&self, refname: &str, action: &Action) -> Result<(), Error> {
+        match action {
+            Action::Create if !self.allow_create_ref.iter().any(|p| glob_match(p, refname)) => {
+                Err(Error::CreateRef(refname.to_string()))
+            }
+            Action::Delete if !self.allow_delete_ref.iter().any(|p| glob_match(p, refname)) => {
+                Err(Error::DeleteRef(refname.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check(&self, path: &str, action: &Action) -> Result<(), Error> {
+        match action {
+            Action::Create => {
+                if self.no_creation {
+                    return Err(Error::BadCreate(path.to_string()));
+                }
+            }
+            Action::Delete => {
+                if self.no_deletion {
+                    return Err(Error::BadDelete(path.to_string()));
+                }
+            }
+            Action::TypeChange => {
+                if self.no_typechange {
+                    return Err(Error::BadTypeChange(path.to_string()));
+                }
+            }
+            Action::Modify => {}
+        }
+
+        if !self.allow_pattern.is_match(path) {
+            return Err(Error::NotAllowed(path.to_string()));
+        }
+        if self.protect_pattern.is_match(path) {
+            return Err(Error::Protected(path.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// rules that apply to some pushers and refs, from a rules file
+#[derive(Debug, Deserialize)]
+struct NamedRules {
+    /// shown when this rule denies something
+    name: String,
+    /// pusher names this rule applies to, or everyone if empty
+    #[serde(default)]
+    users: Vec<String>,
+    /// fingerprints of signing keys this rule applies to, or any if empty
+    #[serde(default)]
+    keys: Vec<String>,
+    /// refs this rule applies to
+    #[serde(default = "full_regex_set", deserialize_with = "de_regex_set")]
+    refs: RegexSet,
+    #[serde(flatten)]
+    rules: Rules,
+}
+
+impl NamedRules {
+    fn applies(&self, user: Option<&str>, key: Option<&str>, refname: &str) -> bool {
+        (self.users.is_empty() || user.is_some_and(|u| self.users.iter().any(|r| r == u)))
+            && (self.keys.is_empty() || key.is_some_and(|k| self.keys.iter().any(|r| r == k)))
+            && self.refs.is_match(refname)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<NamedRules>,
+}
+
+/// everything a push gets checked against
+#[derive(Debug)]
+struct Policy {
+    /// rules from the command line, used when there is no rules file
+    default: Rules,
+    /// rules from the rules file, the first one that applies wins
+    file: Option<Vec<NamedRules>>,
+    allowed_signers: Option<AllowedSigners>,
+    match_committer: bool,
+    /// name of the pusher, as told by whatever is serving the repository
+    user: Option<String>,
+}
+
+impl Policy {
+    fn from_args(args: &PreReceiveArgs) -> Result<Self, Error> {
+        let file = match &args.rules {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(Error::RulesFile)?;
+                let file: RulesFile = toml_edit::de::from_str(&text)?;
+                Some(file.rule)
+            }
+            None => None,
+        };
+        let allowed_signers = args
+            .allowed_signers
+            .as_deref()
+            .map(AllowedSigners::read)
+            .transpose()?;
+        let user = ["GL_USER", "REMOTE_USER"]
+            .into_iter()
+            .find_map(|v| std::env::var(v).ok().filter(|u| !u.is_empty()));
+        Ok(Self {
+            default: Rules::from_args(args)?,
+            file,
+            allowed_signers,
+            match_committer: args.match_committer,
+            user,
+        })
+    }
+
+    /// the rules for a commit on some ref, along with the name of where they came from
+    fn rules_for(&self, refname: &str, key: Option<&str>) -> Result<(Option<&str>, &Rules), Error> {
+        let Some(file) = &self.file else {
+            return Ok((None, &self.default));
+        };
+        file.iter()
+            .find(|r| r.applies(self.user.as_deref(), key, refname))
+            .map(|r| (Some(r.name.as_str()), &r.rules))
+            .ok_or_else(|| Error::NoRule(refname.to_string()))
+    }
+
+    /// check the signature of a commit, giving back the fingerprint of the key that made it
+    ///
+    /// signatures that cannot be checked are only an error if there are allowed signers
+    fn signer(
+        &self,
+        signature: &[u8],
+        data: &[u8],
+        commit: &Commit,
+    ) -> Result<Option<String>, Error> {
+        let Some(signers) = &self.allowed_signers else {
+            return Ok(signing_key(signature, data).ok().map(|k| fingerprint(&k)));
+        };
+        let committer = commit.committer();
+        let email = if self.match_committer {
+            Some(committer.email().map_err(|_| {
+                Error::SignerMismatch(String::from_utf8_lossy(committer.email_bytes()).into_owned())
+            })?)
+        } else {
+            None
+        };
+        signers
+            .verify(signature, data, email, committer.when().seconds())
+            .map(Some)
+    }
+}
+
+fn check_commit(
+    repo: &Repository,
+    policy: &Policy,
+    refname: &str,
+    cid: Oid,
+    pushed: &mut Pushed,
+    report: &mut Report,
+) -> Result<(), Error> {
+    let signature = repo.extract_signature(&cid, None).ok();
+    let commit = repo.find_commit(cid)?;
+    report.at_commit(&commit);
+    let key = if let Some((signature, data)) = &signature {
+        policy.signer(signature, data, &commit).unwrap_or_else(|e| {
+            report.add(e);
+            None
+        })
+    } else {
+        None
+    };
+    // with allowed signers every commit has to be signed, whatever the rules say
+    let must_sign = policy.allowed_signers.is_some();
+    if must_sign && signature.is_none() {
+        report.add(Error::NotSigned);
+    }
+
+    let (name, rules) = match policy.rules_for(refname, key.as_deref()) {
+        Ok(found) => found,
+        Err(e) => {
+            report.add(e);
+            return Ok(());
+        }
+    };
+    report.with_rule(name);
+    if !must_sign {
+        report.check(rules.signed(signature.is_some()));
+    }
+    check_rules(repo, rules, &commit, pushed, report)
+}
+
+fn check_rules(
+    repo: &Repository,
+    rules: &Rules,
+    commit: &Commit,
+    pushed: &mut Pushed,
+    report: &mut Report,
+) -> Result<(), Error> {
+    let tree = commit.tree()?;
+    let parents = commit.parent_count();
+    report.check(rules.parents(parents));
+
+    if parents == 0 {
+        let diff = diff_trees(repo, None, &tree)?;
+        check_deltas(repo, rules, &diff, pushed, report);
+        check_content(repo, rules, &tree, None, &diff, report)?;
+    }
+
+    let ptrees = commit
+        .parents()
+        .map(|p| p.tree())
+        .collect::<Result<Vec<_>, _>>()?;
+    for ptree in &ptrees {
+        pushed.know_tree(ptree)?;
+    }
+    for ptree in &ptrees {
+        let diff = diff_trees(repo, Some(ptree), &tree)?;
+        check_deltas(repo, rules, &diff, pushed, report);
+        check_content(repo, rules, &tree, Some(ptree), &diff, report)?;
+    }
+
+    Ok(())
+}
+
+/// diff two trees, telling apart renames and type changes from other edits
+fn diff_trees<'r>(
+    repo: &'r Repository,
+    old: Option<&Tree>,
+    new: &Tree,
+) -> Result<git2::Diff<'r>, Error> {
+    let mut opts = DiffOptions::new();
+    opts.include_typechange(true);
+    let mut diff = repo.diff_tree_to_tree(old, Some(new), Some(&mut opts))?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+    Ok(diff)
+}
+
+fn file_path<'a>(file: &DiffFile<'a>) -> Option<&'a str> {
+    file.path_bytes().and_then(|p| std::str::from_utf8(p).ok())
+}
+
+fn check_deltas(
+    repo: &Repository,
+    rules: &Rules,
+    diff: &git2::Diff<'_>,
+    pushed: &mut Pushed,
+    report: &mut Report,
+) {
+    for change in diff.deltas() {
+        let (old, new) = (change.old_file(), change.new_file());
+        // renames are checked as deleting the old path and creating the new one
+        let actions: &[(&DiffFile, Action)] = match change.status() {
+            Delta::Added | Delta::Copied => &[(&new, Action::Create)],
+            Delta::Deleted => &[(&old, Action::Delete)],
+            Delta::Modified => &[(&new, Action::Modify)],
+            Delta::Renamed => &[(&old, Action::Delete), (&new, Action::Create)],
+            Delta::Typechange => &[(&new, Action::TypeChange)],
+            _ => continue,
+        };
+
+        for (file, action) in actions {
+            let Some(path) = file_path(file) else {
+                report.add(Error::NonUTF8Path);
+                continue;
+            };
+            report.check(rules.check(path, action));
+            if !matches!(action, Action::Delete) && file.mode() != git2::FileMode::Commit {
+                report.check(check_file(repo, rules, path, file.id(), pushed));
+            }
+        }
+    }
+}
+
+/// check a single ref update, the same way for the hook and for trying it out
+fn check_update(
+    policy: &Policy,
+    repo: &Repository,
+    refname: &str,
+    old: Oid,
+    new: Oid,
+    pushed: &mut Pushed,
+    report: &mut Report,
+) -> Result<(), Error> {
+    report.at_ref(refname);
+    if new.is_zero() {
+        match policy.rules_for(refname, None) {
+            Ok((name, rules)) => {
+                report.with_rule(name);
+                report.check(rules.check_ref(refname, &Action::Delete));
+            }
+            Err(e) => report.add(e),
+        }
+        Ok(())
+    } else if old.is_zero() {
+        create_ref(policy, repo, refname, new, pushed, report)
+    } else {
+        ensure_reachable(policy, repo, refname, old, new, pushed, report)
+    }
+}
+
+fn handle(args: &PreReceiveArgs) -> Result<Report, Error> {
+    let policy = Policy::from_args(args)?;
+    let repo = Repository::open_from_env()?;
+    let stdin = io::stdin().lines();
+    // refs only get updated after this hook, so they are all from before the push
+    let mut pushed = Pushed::new(ref_tips(&repo)?);
+    let mut report = Report::new(args.report_limit);
+
+    for line in stdin {
+        let line = line.map_err(Error::Stdin)?;
+        let split: Vec<_> = line.split(' ').collect();
+        let [old, new, refname] = split[..] else {
+            return Err(Error::InvalidHookInput);
+        };
+
+        let old = Oid::from_str_ext(old, repo.object_format())?;
+        let new = Oid::from_str_ext(new, repo.object_format())?;
+        check_update(&policy, &repo, refname, old, new, &mut pushed, &mut report)?;
+    }
+
+    Ok(report)
+}
+
+fn handle_check_push(args: &CheckPushArgs) -> Result<(String, Report), Error> {
+    let mut policy = Policy::from_args(&args.rules)?;
+    if args.user.is_some() {
+        policy.user.clone_from(&args.user);
+    }
+    let repo = Repository::open_from_env()?;
+    check_range(
+        &policy,
+        &repo,
+        &args.range,
+        args.refname.as_deref(),
+        args.rules.report_limit,
+    )
+}
+
+/// commits that refs point at
+fn ref_tips(repo: &Repository) -> Result<Vec<Oid>, Error> {
+    let mut tips = vec![];
+    for reference in repo.references()? {
+        if let Ok(commit) = reference?.peel_to_commit() {
+            tips.push(commit.id());
+        }
+    }
+    tips.sort();
+    tips.dedup();
+    Ok(tips)
+}
+
+/// commits that refs would point at on a server that has not seen the push yet, which leaves
+/// out local refs that already contain some of the commits in it
+fn tips_before(repo: &Repository, old: Oid, new: Oid) -> Result<Vec<Oid>, Error> {
+    let mut tips = ref_tips(repo)?;
+    // a new ref, where whatever other refs have is there already
+    if old.is_zero() {
+        tips.retain(|&tip| tip != new);
+        return Ok(tips);
+    }
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(new)?;
+    revwalk.hide(old)?;
+    let pushing = revwalk.collect::<Result<HashSet<_>, _>>()?;
+    tips.retain(|&tip| {
+        !pushing.contains(&tip)
+            && !repo
+                .merge_bases(tip, new)
+                // copied so they hash the same, as libgit2 may leave junk past the end of these
+                .is_ok_and(|bases| {
+                    bases
+                        .iter()
+                        .any(|b| Oid::from_bytes(b.as_bytes()).is_ok_and(|b| pushing.contains(&b)))
+                })
+    });
+    Ok(tips)
+}
+
+/// the id git uses for a ref that does not exist, in the hash of this repo
+fn zero_oid(repo: &Repository) -> Oid {
+    match repo.object_format() {
+        ObjectFormat::Sha1 => Oid::ZERO_SHA1,
+        ObjectFormat::Sha256 => Oid::ZERO_SHA256,
+    }
+}
+
+/// check a range like `OLD..NEW` as if it were pushed, or a single commit as a new ref
+fn check_range(
+    policy: &Policy,
+    repo: &Repository,
+    range: &str,
+    refname: Option<&str>,
+    limit: usize,
+) -> Result<(String, Report), Error> {
+    let spec = repo.revparse(range)?;
+    let (old, new) = match (spec.from(), spec.to()) {
+        (Some(old), Some(new)) => (old.peel_to_commit()?.id(), new),
+        (Some(new), None) => (zero_oid(repo), new),
+        _ => return Err(Error::BadRange),
+    };
+    let new = new.peel_to_commit()?.id();
+
+    // figure out the ref from the last part of the range, like main in HEAD~3..main
+    let refname = if let Some(refname) = refname {
+        refname.to_string()
+    } else {
+        let last = range.rsplit("..").next().unwrap_or_default();
+        let last = if last.is_empty() { "HEAD" } else { last };
+        repo.revparse_ext(last)?
+            .1
+            .and_then(|r| r.resolve().ok()?.name().ok().map(str::to_string))
+            .ok_or(Error::UnknownRef)?
+    };
+
+    let mut report = Report::new(limit);
+    check_update(
+        policy,
+        repo,
+        &refname,
+        old,
+        new,
+        &mut Pushed::new(tips_before(repo, old, new)?),
+        &mut report,
+    )?;
+    Ok((refname, report))
+}
+
+/// walk the commits up to `new` that no ref had before the push, like `git rev-list new --not
+/// --all`
+///
+/// parents come first, so a new blob counts at the commit adding it, not one building on that
+fn new_commits<'r>(
+    repo: &'r Repository,
+    pushed: &Pushed,
+    new: Oid,
+) -> Result<git2::Revwalk<'r>, Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    revwalk.push(new)?;
+    for &tip in pushed.tips() {
+        revwalk.hide(tip)?;
+    }
+    Ok(revwalk)
+}
+
+/// check a new ref, along with the commits on it that are not on any other ref yet
+fn create_ref(
+    policy: &Policy,
+    repo: &Repository,
+    refname: &str,
+    new: Oid,
+    pushed: &mut Pushed,
+    report: &mut Report,
+) -> Result<(), Error> {
+    let tip = repo.find_object(new, None)?.peel_to_commit()?;
+    // a bad signature gets reported along with the tip commit itself
+    let key = repo
+        .extract_signature(&tip.id(), None)
+        .ok()
+        .and_then(|(signature, data)| policy.signer(&signature, &data, &tip).ok().flatten());
+    match policy.rules_for(refname, key.as_deref()) {
+        Ok((name, rules)) => {
+            report.with_rule(name);
+            report.check(rules.check_ref(refname, &Action::Create));
+        }
+        // the ref cannot be created anyway, so its commits need no checking
+        Err(e) => {
+            report.add(e);
+            return Ok(());
+        }
+    }
+
+    for cid in new_commits(repo, pushed, tip.id())? {
+        check_commit(repo, policy, refname, cid?, pushed, report)?;
+    }
+
+    Ok(())
+}
+
+fn ensure_reachable(
+    policy: &Policy,
+    repo: &Repository,
+    refname: &str,
+    old: Oid,
+    new: Oid,
+    pushed: &mut Pushed,
+    report: &mut Report,
+) -> Result<(), Error> {
+    if old != new && !repo.graph_descendant_of(new, old)? {
+        report.add(Error::ForcePush);
+        return Ok(());
+    }
+
+    let mut revwalk = new_commits(repo, pushed, new)?;
+    revwalk.hide(old)?;
+    for cid in revwalk {
+        check_commit(repo, policy, refname, cid?, pushed, report)?;
+    }
+
+    Ok(())
+}
+
+pub fn hook(args: &PreReceiveArgs) {
+    match handle(args) {
+        Ok(report) if report.is_empty() => (),
+        Ok(report) => {
+            report.print("rejecting push");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            println!("rejecting push: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn check_push(args: &CheckPushArgs) {
+    match handle_check_push(args) {
+        Ok((refname, report)) if report.is_empty() => {
+            println!("push to {refname} would be accepted");
+        }
+        Ok((_, report)) => {
+            report.print("push would be rejected");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("could not check push: {e}");
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prereceive::*;
+
+    /// an empty repository in a fresh temporary directory
+    fn test_repo(name: &str) -> Repository {
+        let dir = std::env::temp_dir().join(format!("clam-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        Repository::init(dir).unwrap()
+    }
+
+    /// commit some top level files, given as path, content and mode
+    fn test_commit(
+        repo: &Repository,
+        files: &[(&str, &str, git2::FileMode)],
+        parents: &[&Commit],
+    ) -> Oid {
+        let mut tree = repo.treebuilder(None).unwrap();
+        for (path, content, mode) in files {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            tree.insert(path, blob, (*mode).into()).unwrap();
+        }
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let sig = git2::Signature::now("fox", "fox@den").unwrap();
+        repo.commit(None, &sig, &sig, "meow", &tree, parents)
+            .unwrap()
+    }
+
+    /// a policy with the same rules for everyone
+    fn test_policy(rules: &str) -> Policy {
+        Policy {
+            default: toml_edit::de::from_str(rules).unwrap(),
+            file: None,
+            allowed_signers: None,
+            match_committer: false,
+            user: None,
+        }
+    }
+
+    #[test]
+    fn check_glob_match() {
+        assert!(glob_match(
+            "refs/heads/draft/*",
+            "refs/heads/draft/fox/meow"
+        ));
+        assert!(glob_match("refs/tags/*", "refs/tags/v1"));
+        assert!(glob_match("refs/*/v*-rc", "refs/tags/v2-rc"));
+        assert!(!glob_match("refs/heads/draft/*", "refs/heads/main"));
+        assert!(!glob_match("refs/heads/main", "refs/heads/main2"));
+        assert!(!glob_match("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn check_rules_file() {
+        let file: RulesFile = toml_edit::de::from_str(
+            r#"
+[[rule]]
+name = "admins"
+users = ["root"]
+
+[[rule]]
+name = "editors"
+refs = ["^refs/heads/main$"]
+no_deletion = true
+no_typechange = true
+allow_pattern = ["^drafts/"]
+"#,
+        )
+        .unwrap();
+        let mut policy = Policy {
+            default: toml_edit::de::from_str("").unwrap(),
+            file: Some(file.rule),
+            allowed_signers: None,
+            match_committer: false,
+            user: Some("root".to_string()),
+        };
+
+        let (name, rules) = policy.rules_for("refs/heads/main", None).unwrap();
+        assert_eq!(name, Some("admins"));
+        assert!(rules.check("index.org", &Action::Delete).is_ok());
+
+        policy.user = Some("fox".to_string());
+        let (name, rules) = policy.rules_for("refs/heads/main", None).unwrap();
+        assert_eq!(name, Some("editors"));
+        assert!(rules.check("drafts/new.org", &Action::Create).is_ok());
+        assert!(rules.check("index.org", &Action::Modify).is_err());
+        assert!(rules.check("drafts/old.org", &Action::Delete).is_err());
+        assert!(matches!(
+            rules.check("drafts/link.org", &Action::TypeChange),
+            Err(Error::BadTypeChange(_))
+        ));
+
+        assert!(matches!(
+            policy.rules_for("refs/heads/other", None),
+            Err(Error::NoRule(_))
+        ));
+
+        // creating a ref nobody may touch says so once, not again for each commit on it
+        let repo = test_repo("norule");
+        let first = test_commit(&repo, &[], &[]);
+        let second = test_commit(&repo, &[], &[&repo.find_commit(first).unwrap()]);
+        let mut report = Report::new(10);
+        create_ref(
+            &policy,
+            &repo,
+            "refs/heads/other",
+            second,
+            &mut Pushed::default(),
+            &mut report,
+        )
+        .unwrap();
+        assert!(matches!(
+            report.errors().collect::<Vec<_>>()[..],
+            [Error::NoRule(_)]
+        ));
+        _ = std::fs::remove_dir_all(repo.workdir().unwrap());
+    }
+
+    #[test]
+    fn check_typechange_and_rename() {
+        use git2::FileMode::{Blob, Link};
+        let repo = test_repo("typechange");
+        // a page whose link was already broken before it got moved
+        let broken = "[[file:missing.org]]";
+        let first = test_commit(
+            &repo,
+            &[("a.org", "meow", Blob), ("b.org", broken, Blob)],
+            &[],
+        );
+        let first = repo.find_commit(first).unwrap();
+        let second = test_commit(
+            &repo,
+            &[("a.org", "c.org", Link), ("c.org", broken, Blob)],
+            &[&first],
+        );
+        let second = repo.find_commit(second).unwrap();
+
+        let rules: Rules = toml_edit::de::from_str(
+            "no_creation = true\nno_deletion = true\nno_typechange = true\nno_new_redlinks = true",
+        )
+        .unwrap();
+        let mut report = Report::new(10);
+        check_rules(&repo, &rules, &second, &mut Pushed::default(), &mut report).unwrap();
+        let errors: Vec<_> = report.errors().collect();
+        assert!(matches!(
+            errors[..],
+            [
+                Error::BadTypeChange(a),
+                Error::BadDelete(b),
+                Error::BadCreate(c),
+            ] if a == "a.org" && b == "b.org" && c == "c.org"
+        ));
+        _ = std::fs::remove_dir_all(repo.workdir().unwrap());
+    }
+
+    #[test]
+    fn check_all_commits_reported() {
+        use git2::FileMode::Blob;
+        let repo = test_repo("report");
+        let old = test_commit(&repo, &[("a.org", "meow", Blob)], &[]);
+        let first = test_commit(&repo, &[], &[&repo.find_commit(old).unwrap()]);
+        let second = test_commit(
+            &repo,
+            &[("b.org", "woof", Blob)],
+            &[&repo.find_commit(first).unwrap()],
+        );
+
+        let policy = test_policy("no_creation = true\nno_deletion = true");
+        let mut report = Report::new(10);
+        ensure_reachable(
+            &policy,
+            &repo,
+            "refs/heads/main",
+            old,
+            second,
+            &mut Pushed::default(),
+            &mut report,
+        )
+        .unwrap();
+        let errors: Vec<_> = report.errors().collect();
+        assert!(matches!(
+            errors[..],
+            [Error::BadDelete(a), Error::BadCreate(b)] if a == "a.org" && b == "b.org"
+        ));
+        _ = std::fs::remove_dir_all(repo.workdir().unwrap());
+    }
+
+    #[test]
+    fn check_unsigned_reported_once() {
+        let repo = test_repo("unsigned");
+        let cid = test_commit(&repo, &[], &[]);
+        let mut policy = test_policy("require_signing = true");
+        policy.allowed_signers = Some(AllowedSigners::parse(""));
+        let mut report = Report::new(10);
+        check_commit(
+            &repo,
+            &policy,
+            "refs/heads/main",
+            cid,
+            &mut Pushed::default(),
+            &mut report,
+        )
+        .unwrap();
+        assert!(matches!(
+            report.errors().collect::<Vec<_>>()[..],
+            [Error::NotSigned]
+        ));
+        _ = std::fs::remove_dir_all(repo.workdir().unwrap());
+    }
+
+    #[test]
+    fn check_push_range() {
+        use git2::FileMode::Blob;
+        let repo = test_repo("range");
+        let first = test_commit(&repo, &[("a.org", "meow", Blob)], &[]);
+        let main = test_commit(
+            &repo,
+            &[("a.org", "meow", Blob), ("b.org", "woof", Blob)],
+            &[&repo.find_commit(first).unwrap()],
+        );
+        repo.reference("refs/heads/main", main, true, "").unwrap();
+        repo.set_head("refs/heads/main").unwrap();
+        let draft = test_commit(
+            &repo,
+            &[
+                ("a.org", "meow", Blob),
+                ("b.org", "woof", Blob),
+                ("c.org", "purr", Blob),
+            ],
+            &[&repo.find_commit(main).unwrap()],
+        );
+        repo.reference("refs/heads/draft", draft, true, "").unwrap();
+        let policy = test_policy("no_creation = true");
+
+        let (refname, report) = check_range(&policy, &repo, "main~1..main", None, 10).unwrap();
+        assert_eq!(refname, "refs/heads/main");
+        let errors: Vec<_> = report.errors().collect();
+        assert!(matches!(errors[..], [Error::BadCreate(b)] if b == "b.org"));
+
+        // only the commits that are not on main yet get checked for a new ref
+        let (refname, report) = check_range(&policy, &repo, "draft", None, 10).unwrap();
+        assert_eq!(refname, "refs/heads/draft");
+        let errors: Vec<_> = report.errors().collect();
+        assert!(matches!(
+            errors[..],
+            [Error::CreateRef(r), Error::BadCreate(c)] if r == "refs/heads/draft" && c == "c.org"
+        ));
+
+        // a new ref for commits that are on another ref already has nothing new to check
+        let mut report = Report::new(10);
+        let mut pushed = Pushed::new(ref_tips(&repo).unwrap());
+        let zero = zero_oid(&repo);
+        check_update(
+            &policy,
+            &repo,
+            "refs/heads/copy",
+            zero,
+            draft,
+            &mut pushed,
+            &mut report,
+        )
+        .unwrap();
+        let errors: Vec<_> = report.errors().collect();
+        assert!(matches!(errors[..], [Error::CreateRef(_)]));
+        _ = std::fs::remove_dir_all(repo.workdir().unwrap());
+    }
+
+    #[test]
+    fn check_known_blobs() {
+        use git2::FileMode::Blob;
+        let repo = test_repo("known");
+        let big = "meow".repeat(100);
+        let other = test_commit(&repo, &[("big.bin", &big, Blob)], &[]);
+        repo.reference("refs/heads/other", other, true, "").unwrap();
+        let main = test_commit(
+            &repo,
+            &[
+                ("copy.bin", &big, Blob),
+                ("a.bin", "woof", Blob),
+                ("b.bin", "woof", Blob),
+            ],
+            &[],
+        );
+
+        let policy = test_policy("max_push_size = 4\nallow_create_ref = [\"*\"]");
+        let mut report = Report::new(10);
+        let mut pushed = Pushed::new(ref_tips(&repo).unwrap());
+        let zero = zero_oid(&repo);
+        check_update(
+            &policy,
+            &repo,
+            "refs/heads/main",
+            zero,
+            main,
+            &mut pushed,
+            &mut report,
+        )
+        .unwrap();
+        assert!(report.is_empty());
+        _ = std::fs::remove_dir_all(repo.workdir().unwrap());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::Error;
+use git2::Commit;
+
+/// something a push is not allowed to do, and where it happened
+#[derive(Debug)]
+struct Violation {
+    refname: String,
+    commit: Option<String>,
+    error: Error,
+}
+
+/// everything wrong with a push, so it can all be fixed in one go
+#[derive(Debug)]
+pub struct Report {
+    violations: Vec<Violation>,
+    /// how many violations there were, including the ones past the limit
+    total: usize,
+    limit: usize,
+    refname: String,
+    /// short id and summary of the commit being checked
+    commit: Option<String>,
+    /// name of the rule the current commit is checked with, if it came from a rules file
+    rule: Option<String>,
+}
+
+impl Report {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            violations: vec![],
+            total: 0,
+            limit,
+            refname: String::new(),
+            commit: None,
+            rule: None,
+        }
+    }
+
+    /// start checking a ref, outside of any commit
+    pub fn at_ref(&mut self, refname: &str) {
+        self.refname = refname.to_string();
+        self.commit = None;
+        self.rule = None;
+    }
+
+    pub fn at_commit(&mut self, commit: &Commit) {
+        let id = commit.id().to_string();
+        let short = id.get(..12).unwrap_or(&id);
+        self.commit = Some(match commit.summary() {
+            Ok(Some(summary)) => format!("{short} {summary}"),
+            _ => short.to_string(),
+        });
+        self.rule = None;
+    }
+
+    pub fn with_rule(&mut self, rule: Option<&str>) {
+        self.rule = rule.map(str::to_string);
+    }
+
+    pub fn add(&mut self, error: Error) {
+        self.total += 1;
+        if self.violations.len() >= self.limit {
+            return;
+        }
+        let error = match &self.rule {
+            Some(rule) => Error::DeniedBy(rule.clone(), Box::new(error)),
+            None => error,
+        };
+        self.violations.push(Violation {
+            refname: self.refname.clone(),
+            commit: self.commit.clone(),
+            error,
+        });
+    }
+
+    /// add the error of a check, if it failed
+    pub fn check(&mut self, res: Result<(), Error>) {
+        if let Err(e) = res {
+            self.add(e);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    #[cfg(test)]
+    pub fn errors(&self) -> impl Iterator<Item = &Error> {
+        self.violations.iter().map(|v| &v.error)
+    }
+
+    /// print violations grouped by ref and commit, after saying what happens to the push
+    pub fn print(&self, verdict: &str) {
+        println!("{verdict}, found {} problems:", self.total);
+        let mut last: Option<(&str, Option<&str>)> = None;
+        for v in &self.violations {
+            let here = (v.refname.as_str(), v.commit.as_deref());
+            if last != Some(here) {
+                // problems with the ref itself get their own heading, even after some commits
+                if last.is_none_or(|(r, c)| r != here.0 || (c.is_some() && here.1.is_none())) {
+                    println!("{}", v.refname);
+                }
+                if let Some(commit) = here.1 {
+                    println!("  {commit}");
+                }
+                last = Some(here);
+            }
+            let indent = if v.commit.is_some() { "    " } else { "  " };
+            println!("{indent}{}", v.error);
+        }
+        if self.total > self.violations.len() {
+            println!("...and {} more", self.total - self.violations.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prereceive::report::*;
+
+    #[test]
+    fn check_report() {
+        let mut report = Report::new(2);
+        report.at_ref("refs/heads/main");
+        report.check(Ok(()));
+        assert!(report.is_empty());
+
+        report.check(Err(Error::Merge));
+        report.with_rule(Some("editors"));
+        report.add(Error::NotSigned);
+        report.add(Error::ForcePush);
+        assert!(!report.is_empty());
+        assert_eq!(report.total, 3);
+        assert!(matches!(
+            report.errors().collect::<Vec<_>>()[..],
+            [Error::Merge, Error::DeniedBy(rule, _)] if rule == "editors"
+        ));
+        assert!(
+            report
+                .violations
+                .iter()
+                .all(|v| v.refname == "refs/heads/main")
+        );
+    }
+}
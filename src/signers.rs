@@ -0,0 +1,279 @@
+use crate::Error;
+use chrono::NaiveDate;
+use ssh_key::{HashAlg, PublicKey, SshSig, public::KeyData};
+use std::path::Path;
+
+/// namespace git uses when making ssh signatures
+const NAMESPACE: &str = "git";
+
+/// one line of an allowed signers file
+#[derive(Debug)]
+struct Signer {
+    /// comma separated patterns of identities this key may sign as
+    principals: String,
+    namespaces: Option<String>,
+    valid_after: Option<i64>,
+    valid_before: Option<i64>,
+    key: KeyData,
+}
+
+impl Signer {
+    /// whether this key may sign things in git at this time
+    fn usable(&self, time: i64) -> bool {
+        self.namespaces
+            .as_deref()
+            .is_none_or(|n| match_pattern_list(n, NAMESPACE))
+            && self.valid_after.is_none_or(|t| time >= t)
+            && self.valid_before.is_none_or(|t| time < t)
+    }
+}
+
+/// the keys that are trusted to sign commits, in the format of git's
+/// `gpg.ssh.allowedSignersFile`
+#[derive(Debug)]
+pub struct AllowedSigners(Vec<Signer>);
+
+/// split at the first separator that is not inside quotes
+fn split_unquoted(line: &str, is_sep: impl Fn(char) -> bool) -> (&str, &str) {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if is_sep(c) && !quoted => return (&line[..i], &line[i..]),
+            _ => (),
+        }
+    }
+    (line, "")
+}
+
+/// split off the next whitespace separated field, keeping quoted parts together
+fn next_field(line: &str) -> (&str, &str) {
+    split_unquoted(line.trim_start(), char::is_whitespace)
+}
+
+/// match an ssh-style pattern, where `*` matches anything and `?` any single character
+fn match_pattern(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => {
+                let Some((bp, bt)) = backtrack else {
+                    return false;
+                };
+                backtrack = Some((bp, bt + 1));
+                p = bp + 1;
+                t = bt + 1;
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// match a comma separated list of patterns, any of which may be negated with `!`
+fn match_pattern_list(list: &str, text: &str) -> bool {
+    let mut matched = false;
+    for pattern in list.split(',') {
+        if let Some(pattern) = pattern.strip_prefix('!') {
+            if match_pattern(pattern, text) {
+                return false;
+            }
+        } else if match_pattern(pattern, text) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// parse a timestamp like `YYYYMMDD[HHMM[SS]]`
+///
+/// FIXME: times without a trailing `Z` should be in the local timezone, not utc
+fn parse_time(time: &str) -> Option<i64> {
+    let time = time.strip_suffix(['Z', 'z']).unwrap_or(time);
+    if !matches!(time.len(), 8 | 12 | 14) || !time.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| time.get(range).map_or(Some(0), |n| n.parse().ok());
+    let date = NaiveDate::from_ymd_opt(num(0..4)?.try_into().ok()?, num(4..6)?, num(6..8)?)?;
+    Some(
+        date.and_hms_opt(num(8..10)?, num(10..12)?, num(12..14)?)?
+            .and_utc()
+            .timestamp(),
+    )
+}
+
+fn parse_line(line: &str) -> Option<Signer> {
+    let (principals, rest) = next_field(line);
+    let (mut field, mut rest) = next_field(rest);
+
+    let mut namespaces = None;
+    let mut valid_after = None;
+    let mut valid_before = None;
+    // options come before the key, if there are any
+    if !field.starts_with("ssh-") && !field.starts_with("ecdsa-") && !field.starts_with("sk-") {
+        let mut options = field;
+        while !options.is_empty() {
+            let (option, after) = split_unquoted(options, |c| c == ',');
+            options = after.strip_prefix(',').unwrap_or(after);
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            let value = value.trim_matches('"');
+            match name.to_ascii_lowercase().as_str() {
+                "namespaces" => namespaces = Some(value.to_string()),
+                "valid-after" => valid_after = Some(parse_time(value)?),
+                "valid-before" => valid_before = Some(parse_time(value)?),
+                // certificates are not supported, and should not end up trusted by accident
+                _ => return None,
+            }
+        }
+        (field, rest) = next_field(rest);
+    }
+
+    let (key, _comment) = next_field(rest);
+    let key = PublicKey::from_openssh(&format!("{field} {key}")).ok()?;
+    Some(Signer {
+        principals: principals.trim_matches('"').to_string(),
+        namespaces,
+        valid_after,
+        valid_before,
+        key: key.key_data().clone(),
+    })
+}
+
+/// how keys are named in rules and errors
+pub fn fingerprint(key: &PublicKey) -> String {
+    key.fingerprint(HashAlg::Sha256).to_string()
+}
+
+/// check that an ssh signature is valid, without caring who made it
+pub fn signing_key(signature: &[u8], data: &[u8]) -> Result<PublicKey, Error> {
+    let signature = SshSig::from_pem(signature).map_err(|_| Error::BadSignature)?;
+    let key = PublicKey::from(signature.public_key().clone());
+    key.verify(NAMESPACE, data, &signature)
+        .map_err(|_| Error::BadSignature)?;
+    Ok(key)
+}
+
+impl AllowedSigners {
+    pub fn parse(text: &str) -> Self {
+        let mut signers = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_line(line) {
+                Some(signer) => signers.push(signer),
+                None => eprintln!("ignoring allowed signers line {}", n + 1),
+            }
+        }
+        Self(signers)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path).map_err(Error::AllowedSigners)?;
+        Ok(Self::parse(&text))
+    }
+
+    /// check an ssh signature over some commit data, giving back the fingerprint of its key
+    ///
+    /// the signing key must be in the file and valid at `time`. if `email` is given, it must
+    /// also match the principals of that key
+    pub fn verify(
+        &self,
+        signature: &[u8],
+        data: &[u8],
+        email: Option<&str>,
+        time: i64,
+    ) -> Result<String, Error> {
+        let key = signing_key(signature, data)?;
+        let mut signers = self
+            .0
+            .iter()
+            .filter(|s| s.key == *key.key_data() && s.usable(time))
+            .peekable();
+        let fingerprint = fingerprint(&key);
+        if signers.peek().is_none() {
+            return Err(Error::UntrustedSigner(fingerprint));
+        }
+        if let Some(email) = email
+            && !signers.any(|s| match_pattern_list(&s.principals, email))
+        {
+            return Err(Error::SignerMismatch(email.to_string()));
+        }
+
+        Ok(fingerprint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signers::*;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIH7k5AA1EGWS6FUGv9Z4uvqenztVTL6GJdRGo5qH5qvt";
+    const SIGNATURE: &[u8] = b"-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAgfuTkADUQZZLoVQa/1ni6+p6fO1
+VMvoYl1Eajmofmq+0AAAADZ2l0AAAAAAAAAAZzaGE1MTIAAABTAAAAC3NzaC1lZDI1NTE5
+AAAAQKDeYZo0D6F3CMO4h7fz0qwfHF9eyDWjyGxWwKaA8IdvUNx3wxdkgxNFwvuHmBuopT
+71i15Y2+okBoc1nCbrFgs=
+-----END SSH SIGNATURE-----
+";
+
+    #[test]
+    fn check_patterns() {
+        assert!(match_pattern("*@example.org", "fox@example.org"));
+        assert!(match_pattern("f?x@*", "fox@den"));
+        assert!(!match_pattern("f?x@*", "FOX@den"));
+        assert!(!match_pattern("*@example.org", "fox@example.com"));
+        assert!(match_pattern_list("*@den,!bad@den", "fox@den"));
+        assert!(!match_pattern_list("*@den,!bad@den", "bad@den"));
+    }
+
+    #[test]
+    fn check_verify() {
+        let signers = AllowedSigners::parse(&format!(
+            "# comment\nfox@den namespaces=\"git\" {KEY} comment\n\"*@example.org\" valid-before=19700102 {KEY}\n"
+        ));
+        assert_eq!(signers.0.len(), 2);
+        assert_eq!(
+            parse_line(&format!(
+                "fox@den valid-after=19700101,namespaces=\"git,file\" {KEY}"
+            ))
+            .unwrap()
+            .namespaces
+            .as_deref(),
+            Some("git,file")
+        );
+
+        let data = b"meow\n";
+        assert!(signers.verify(SIGNATURE, data, None, 0).is_ok());
+        assert!(signers.verify(SIGNATURE, data, Some("fox@den"), 0).is_ok());
+        assert!(
+            signers
+                .verify(SIGNATURE, data, Some("me@example.org"), 0)
+                .is_ok()
+        );
+        assert!(matches!(
+            signers.verify(SIGNATURE, data, Some("me@example.org"), 1 << 40),
+            Err(Error::SignerMismatch(_))
+        ));
+        assert!(matches!(
+            signers.verify(SIGNATURE, b"woof\n", None, 0),
+            Err(Error::BadSignature)
+        ));
+        assert!(matches!(
+            AllowedSigners::parse("").verify(SIGNATURE, data, None, 0),
+            Err(Error::UntrustedSigner(_))
+        ));
+    }
+}
@@ -0,0 +1,537 @@
+use crate::{
+    Error, RepoArgs, STYLESHEET,
+    atom::AtomDateTime,
+    config::{OverrideConfig, read_config},
+    include::apply_includes,
+    macros,
+    output::site_index,
+    util::single::{Bundle, Entry, LocalImages, TocEntry, generate_entries, generate_pages},
+};
+use boilerplate::Trusted;
+use git2::{Blob, Commit, Repository};
+use orgize::{Org, ast::Entity, export::HtmlEscape};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write as _,
+    io::Write as _,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use url::Url;
+
+static CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles>
+<rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+</rootfiles>
+</container>
+"#;
+
+#[derive(boilerplate::Boilerplate)]
+struct EpubPackageXml<'a> {
+    id: &'a str,
+    title: &'a str,
+    lang: &'a str,
+    creator: &'a str,
+    source: Option<&'a str>,
+    modified: &'a AtomDateTime,
+    entries: &'a [Entry<'a>],
+    images: &'a [(String, &'static str, Blob<'a>)],
+}
+
+#[derive(boilerplate::Boilerplate)]
+struct EpubNavXml<'a> {
+    title: &'a str,
+    lang: &'a str,
+    toc: &'a str,
+}
+
+#[derive(boilerplate::Boilerplate)]
+struct EpubChapterXml<'a> {
+    entry: &'a Entry<'a>,
+    body: &'a str,
+    lang: &'a str,
+}
+
+/// minimal writer for uncompressed zip archives, which is all an epub needs
+#[derive(Default)]
+struct Zip {
+    data: Vec<u8>,
+    central: Vec<u8>,
+    count: u16,
+}
+
+impl Zip {
+    fn add(&mut self, name: &str, content: &[u8]) -> Result<(), Error> {
+        let too_big = |_| Error::ZipTooBig(name.to_string());
+        let crc = crc32(content).to_le_bytes();
+        let size = u32::try_from(content.len()).map_err(too_big)?.to_le_bytes();
+        let offset = u32::try_from(self.data.len())
+            .map_err(too_big)?
+            .to_le_bytes();
+        let name_len = u16::try_from(name.len()).map_err(too_big)?.to_le_bytes();
+        self.count = self
+            .count
+            .checked_add(1)
+            .ok_or_else(|| Error::ZipTooBig(format!("{name}, too many files")))?;
+
+        // version needed, flags (utf-8 names), method (stored), time and date (1980-01-01)
+        let mut common = vec![20, 0, 0, 8, 0, 0, 0, 0, 0x21, 0];
+        common.extend(crc);
+        common.extend(size);
+        common.extend(size);
+        common.extend(name_len);
+
+        self.data.extend(b"PK\x03\x04");
+        self.data.extend(&common);
+        self.data.extend([0, 0]);
+        self.data.extend(name.as_bytes());
+        self.data.extend(content);
+
+        self.central.extend(b"PK\x01\x02");
+        self.central.extend([20, 0]);
+        self.central.extend(&common);
+        // extra field, comment, disk number, internal and external attributes
+        self.central.extend([0; 12]);
+        self.central.extend(offset);
+        self.central.extend(name.as_bytes());
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, Error> {
+        let too_big = |_| Error::ZipTooBig("central directory".to_string());
+        let offset = u32::try_from(self.data.len())
+            .map_err(too_big)?
+            .to_le_bytes();
+        let size = u32::try_from(self.central.len())
+            .map_err(too_big)?
+            .to_le_bytes();
+        let count = self.count.to_le_bytes();
+
+        self.data.append(&mut self.central);
+        self.data.extend(b"PK\x05\x06");
+        self.data.extend([0; 4]);
+        self.data.extend(count);
+        self.data.extend(count);
+        self.data.extend(size);
+        self.data.extend(offset);
+        self.data.extend([0, 0]);
+        Ok(self.data)
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// the character a named html entity stands for, as far as org knows it
+fn named_entity(name: &str) -> Option<char> {
+    let org = Org::parse(format!("\\{name}{{}}"));
+    let entity = org.first_node::<Entity>()?;
+    if entity.html() != format!("&{name};") {
+        return None;
+    }
+    // a few are written like \x00A0 in org's table
+    let utf8 = entity.utf8();
+    match utf8.strip_prefix("\\x") {
+        Some(hex) => char::from_u32(u32::from_str_radix(hex, 16).ok()?),
+        None => utf8.chars().next(),
+    }
+}
+
+/// make character references in html text fit for xml, which only knows a handful of named
+/// ones, and escape any `&` that does not start a reference
+fn xml_refs(text: &str, out: &mut String) {
+    const XML: &[&str] = &["amp", "lt", "gt", "quot", "apos"];
+
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let name = rest
+            .find(';')
+            .map(|end| &rest[..end])
+            .filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_alphanumeric() || c == '#'));
+        let Some(name) = name else {
+            out.push_str("&amp;");
+            continue;
+        };
+
+        let numeric = name.strip_prefix('#').is_some_and(|n| {
+            n.strip_prefix(['x', 'X']).map_or_else(
+                || n.parse::<u32>().is_ok(),
+                |hex| u32::from_str_radix(hex, 16).is_ok(),
+            )
+        });
+        if numeric || XML.contains(&name) {
+            _ = write!(out, "&{name};");
+        } else if let Some(c) = named_entity(name) {
+            out.push(c);
+        } else {
+            out.push_str("&amp;");
+            continue;
+        }
+        rest = &rest[name.len() + 1..];
+    }
+    out.push_str(rest);
+}
+
+/// turn the html output of [`crate::output::html::Handler`] into well-formed xhtml, by
+/// quoting attributes, closing void elements and fixing up character references
+fn xhtmlify(html: &str) -> String {
+    const VOID: &[&str] = &[
+        "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source",
+        "track", "wbr",
+    ];
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        xml_refs(&rest[..start], &mut out);
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            let end = comment.find("-->").map_or(comment.len(), |e| e + 3);
+            out.push_str("<!--");
+            out.push_str(&comment[..end]);
+            rest = &comment[end..];
+            continue;
+        }
+        if rest.starts_with("</") || rest.starts_with("<!") || rest.starts_with("<?") {
+            let end = rest.find('>').map_or(rest.len(), |e| e + 1);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        let tag = &rest[1..];
+        let name_len = tag
+            .find(|c: char| c.is_ascii_whitespace() || matches!(c, '/' | '>'))
+            .unwrap_or(tag.len());
+        let name = &tag[..name_len];
+        out.push('<');
+        out.push_str(name);
+
+        let mut attrs = &tag[name_len..];
+        loop {
+            attrs = attrs.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+            if let Some(r) = attrs.strip_prefix('>') {
+                attrs = r;
+                break;
+            }
+            if attrs.is_empty() {
+                break;
+            }
+
+            let key_len = attrs
+                .find(|c: char| c.is_ascii_whitespace() || matches!(c, '=' | '>' | '/'))
+                .unwrap_or(attrs.len());
+            let key = &attrs[..key_len];
+            attrs = &attrs[key_len..];
+
+            let value = if let Some(v) = attrs.strip_prefix('=') {
+                if let Some(q) = v.chars().next().filter(|&q| matches!(q, '"' | '\'')) {
+                    let v = &v[1..];
+                    let end = v.find(q).unwrap_or(v.len());
+                    attrs = v.get(end + 1..).unwrap_or("");
+                    &v[..end]
+                } else {
+                    let end = v
+                        .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                        .unwrap_or(v.len());
+                    attrs = &v[end..];
+                    &v[..end]
+                }
+            } else {
+                // boolean attribute
+                key
+            };
+
+            if !key.is_empty() {
+                _ = write!(out, " {key}=\"");
+                let mut escaped = String::new();
+                xml_refs(value, &mut escaped);
+                out.push_str(&escaped.replace('<', "&lt;").replace('"', "&quot;"));
+                out.push('"');
+            }
+        }
+
+        if VOID.contains(&name.to_ascii_lowercase().as_str()) {
+            out.push_str("/>");
+        } else {
+            out.push('>');
+        }
+        rest = attrs;
+    }
+    xml_refs(rest, &mut out);
+
+    out
+}
+
+/// build the nested list of pages and their headlines for the navigation document
+fn nav_list(entries: &[Entry], toc: &HashMap<String, Vec<TocEntry>>) -> String {
+    let mut out = String::from("<ol>");
+
+    for entry in entries {
+        _ = write!(
+            out,
+            "<li><a href=\"{}.xhtml\">{}</a>",
+            entry.slug,
+            HtmlEscape(entry.title)
+        );
+
+        // stack of open list items, and if they have a nested list yet
+        let mut open: Vec<(usize, bool)> = vec![];
+        let mut has_children = false;
+
+        for TocEntry { level, id, title } in toc.get(entry.slug).into_iter().flatten() {
+            while let Some(&(lvl, kids)) = open.last()
+                && lvl >= *level
+            {
+                open.pop();
+                if kids {
+                    out.push_str("</ol>");
+                }
+                out.push_str("</li>");
+            }
+
+            let parent = open.last_mut().map_or(&mut has_children, |o| &mut o.1);
+            if !*parent {
+                out.push_str("<ol>");
+                *parent = true;
+            }
+
+            let title = if title.is_empty() { "untitled" } else { title };
+            _ = write!(
+                out,
+                "<li><a href=\"{}.xhtml#{id}\">{}</a>",
+                entry.slug,
+                HtmlEscape(title)
+            );
+            open.push((*level, false));
+        }
+
+        while let Some((_, kids)) = open.pop() {
+            if kids {
+                out.push_str("</ol>");
+            }
+            out.push_str("</li>");
+        }
+        if has_children {
+            out.push_str("</ol>");
+        }
+        out.push_str("</li>");
+    }
+
+    out.push_str("</ol>");
+    out
+}
+
+/// look up collected images in the tree, along with their href and mime type
+fn find_images<'a>(
+    repo: &'a Repository,
+    tree: &git2::Tree,
+    images: &BTreeSet<PathBuf>,
+) -> Vec<(String, &'static str, Blob<'a>)> {
+    images
+        .iter()
+        .filter_map(|path| {
+            let mime = super::image_mime(path)?;
+            let href = Url::from_file_path(Path::new("/").join(path)).ok()?;
+            let blob = tree
+                .get_path(path)
+                .and_then(|e| e.to_object(repo))
+                .ok()
+                .and_then(|o| o.into_blob().ok());
+            let Some(blob) = blob else {
+                eprintln!("skipping missing image {}", path.display());
+                return None;
+            };
+            Some((href.path()[1..].to_string(), mime, blob))
+        })
+        .collect()
+}
+
+pub fn print_epub(repo: &Repository, commit: &Commit, args: &RepoArgs) {
+    let tree = commit.tree().unwrap();
+    let mut hmeta = crate::git::make_time_tree(repo, commit.id()).unwrap();
+    let org_cfg = crate::default_org_cfg();
+    let overrides = OverrideConfig {
+        url: args.url.clone(),
+        inline: args.inline,
+        format: args.format,
+    };
+    let config = read_config(&overrides);
+    let mut pages = BTreeMap::new();
+    let mut links = HashMap::new();
+    let mut images = BTreeSet::new();
+    let buckets = config.as_ref().map_or(&[][..], |c| &c.site_buckets);
+    let site_macros = macros::site_macros(repo, &tree, config.as_ref(), &org_cfg);
+    let mut bundle = Bundle {
+        ext: Some("xhtml"),
+        images: LocalImages::Collect(&mut images),
+        site: Rc::new(site_index(repo, &tree, &org_cfg, buckets, &site_macros).unwrap()),
+        ..Default::default()
+    };
+
+    let includes = generate_pages(
+        repo,
+        &tree,
+        &org_cfg,
+        &mut pages,
+        &mut links,
+        &mut bundle,
+        &site_macros,
+    );
+    apply_includes(&mut hmeta, &includes);
+    let Bundle { toc, .. } = bundle;
+
+    let source = config.as_ref().map(|c| c.url.as_str());
+    let footer = config
+        .as_ref()
+        .and_then(|c| c.extra_footer.as_deref())
+        .map(xhtmlify);
+    let mut entries = generate_entries(
+        &pages,
+        &links,
+        &hmeta,
+        footer.as_deref(),
+        source,
+        args.format.to_ext(),
+    );
+    // the index page is the natural place to start reading
+    entries.sort_by_key(|e| e.slug != "index-org");
+
+    let index = entries.first().filter(|e| e.slug == "index-org");
+    let author = commit.author();
+    let title = index.map_or("untitled", |e| e.title);
+    let creator = index.map_or_else(|| author.name().unwrap_or("unknown"), |e| e.metadata.author);
+    let lang = pages
+        .get("index-org")
+        .and_then(|p| p.keywords.language.as_deref())
+        .unwrap_or("en");
+    let id = config
+        .as_ref()
+        .and_then(|c| c.id.clone())
+        .unwrap_or_else(|| format!("urn:git:{}", commit.id()));
+    let modified =
+        AtomDateTime::new(commit.time().seconds()).expect("commit time should be reasonable");
+
+    let images = find_images(repo, &tree, &images);
+
+    let package = EpubPackageXml {
+        id: &id,
+        title,
+        lang,
+        creator,
+        source,
+        modified: &modified,
+        entries: &entries,
+        images: &images,
+    };
+    let nav = EpubNavXml {
+        title,
+        lang,
+        toc: &nav_list(&entries, &toc),
+    };
+
+    let files = entries.iter().map(|entry| {
+        let chapter = EpubChapterXml {
+            entry,
+            body: &xhtmlify(entry.body),
+            lang,
+        };
+        (format!("OEBPS/{}.xhtml", entry.slug), chapter.to_string())
+    });
+    match pack(&package, &nav, files, &images) {
+        Ok(epub) => std::io::stdout().lock().write_all(&epub).unwrap(),
+        Err(e) => {
+            eprintln!("failed to make epub: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// put together the epub archive, from its package and navigation documents, chapters and images
+fn pack(
+    package: &EpubPackageXml,
+    nav: &EpubNavXml,
+    chapters: impl Iterator<Item = (String, String)>,
+    images: &[(String, &'static str, Blob)],
+) -> Result<Vec<u8>, Error> {
+    let mut zip = Zip::default();
+    // must be the very first file, so readers can sniff it
+    zip.add("mimetype", b"application/epub+zip")?;
+    zip.add("META-INF/container.xml", CONTAINER_XML.as_bytes())?;
+    zip.add("OEBPS/content.opf", package.to_string().as_bytes())?;
+    zip.add("OEBPS/nav.xhtml", nav.to_string().as_bytes())?;
+    zip.add("OEBPS/style.css", STYLESHEET)?;
+    for (path, chapter) in chapters {
+        zip.add(&path, chapter.as_bytes())?;
+    }
+    for (href, _, blob) in images {
+        let path = percent_encoding::percent_decode_str(href).decode_utf8_lossy();
+        zip.add(&format!("OEBPS/{path}"), blob.content())?;
+    }
+    zip.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::epub::*;
+
+    #[test]
+    fn check_zip_limits() {
+        let mut zip = Zip {
+            count: u16::MAX - 1,
+            ..Default::default()
+        };
+        assert!(zip.add("a", b"meow").is_ok());
+        assert!(matches!(zip.add("b", b"meow"), Err(Error::ZipTooBig(_))));
+        assert!(zip.finish().is_ok_and(|z| z.starts_with(b"PK\x03\x04")));
+    }
+
+    #[test]
+    fn check_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn snapshot_xhtml() {
+        assert_eq!(
+            xhtmlify(
+                r#"<h2 tabindex=-1 id="meow">meow</h2><p>a<br>b</p><!-- <br> --><li><input type=checkbox disabled checked> <img class=chat-head width=64 src="faces/fox.png" alt='"fox"'></li><hr/>"#
+            ),
+            r#"<h2 tabindex="-1" id="meow">meow</h2><p>a<br/>b</p><!-- <br> --><li><input type="checkbox" disabled="disabled" checked="checked"/> <img class="chat-head" width="64" src="faces/fox.png" alt="&quot;fox&quot;"/></li><hr/>"#
+        );
+    }
+
+    #[test]
+    fn check_xhtml_refs() {
+        let mut handler = crate::output::html::Handler::default();
+        Org::parse("a\\nbsp{}b \\alpha{} & c").traverse(&mut handler);
+        let xhtml = xhtmlify(&handler.exp.finish());
+        assert!(xhtml.contains("a\u{a0}b α &amp; c"), "{xhtml}");
+
+        assert_eq!(
+            xhtmlify(
+                r#"<a href=/x?a=1&b=2 title='&lt;"&hellip;" <me>' id="&#160;&bogus;">&copy;&#xA0;</a>"#
+            ),
+            r#"<a href="/x?a=1&amp;b=2" title="&lt;&quot;…&quot; &lt;me>" id="&#160;&amp;bogus;">©&#xA0;</a>"#
+        );
+    }
+}
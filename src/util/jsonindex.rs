@@ -1,9 +1,14 @@
 use crate::{
-    OutputFormat, RepoArgs, default_org_cfg,
+    OutputFormat, RepoArgs,
+    config::{OverrideConfig, read_config},
+    default_org_cfg,
+    include::{IncludeMap, expand_includes},
+    macros::{expand_macros, site_macros},
     output::{gmi::GmiExport, infer_title},
     util::map_files,
 };
 use git2::{Blob, Commit, Repository};
+use orgize::ParseConfig;
 use serde::Serialize;
 use std::{ffi::OsStr, path::PathBuf};
 
@@ -15,6 +20,15 @@ struct Entry {
 }
 
 pub fn print_index(repo: &Repository, commit: &Commit, args: &RepoArgs) {
+    let tree = commit.tree().unwrap();
+    let org_cfg = default_org_cfg();
+    let config = read_config(&OverrideConfig {
+        url: args.url.clone(),
+        inline: args.inline,
+        format: args.format,
+    });
+    let site_macros = site_macros(repo, &tree, config.as_ref(), &org_cfg);
+    let mut includes = IncludeMap::new();
     map_files(repo, commit, |name, blob| {
         let Some(entry) = (match name
             .extension()
@@ -22,7 +36,12 @@ pub fn print_index(repo: &Repository, commit: &Commit, args: &RepoArgs) {
             .map(str::to_ascii_lowercase)
             .as_deref()
         {
-            Some("org") => get_entry_org(name, &blob, args.format),
+            Some("org") => {
+                let file =
+                    expand_includes(repo, &tree, &org_cfg, &name, blob.content(), &mut includes);
+                let file = expand_macros(&name, &file, &site_macros, &org_cfg);
+                get_entry_org(name, &file, &org_cfg, args.format)
+            }
             _ => get_entry_raw(name, &blob),
         }) else {
             return;
@@ -32,11 +51,16 @@ pub fn print_index(repo: &Repository, commit: &Commit, args: &RepoArgs) {
     .unwrap();
 }
 
-fn get_entry_org(mut path: PathBuf, blob: &Blob, outfmt: OutputFormat) -> Option<Entry> {
+fn get_entry_org(
+    mut path: PathBuf,
+    file: &[u8],
+    org_cfg: &ParseConfig,
+    outfmt: OutputFormat,
+) -> Option<Entry> {
     path.set_extension(outfmt.to_ext());
 
-    let fstr = std::str::from_utf8(blob.content()).ok()?;
-    let res = default_org_cfg().parse(fstr);
+    let fstr = std::str::from_utf8(file).ok()?;
+    let res = org_cfg.clone().parse(fstr);
     let title = res.title().unwrap_or_else(|| infer_title(&path));
     let mut export = GmiExport::default();
     res.traverse(&mut export);
@@ -0,0 +1,340 @@
+use super::{
+    line_col, map_org,
+    report::{Problem, Rule, report},
+};
+use crate::{
+    LinkcheckArgs,
+    helpers::{file_root, org_link_urls},
+};
+use git2::{Commit, Repository};
+use orgize::{Org, rowan::ast::AstNode};
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use ureq::Agent;
+use url::Url;
+
+/// how a url fared the last time it was checked
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Checked {
+    /// http status, or [`None`] if there was no response at all
+    status: Option<u16>,
+    /// what went wrong if there was no response
+    error: Option<String>,
+    /// unix timestamp of when it was checked
+    time: u64,
+}
+
+impl Checked {
+    fn ok(&self) -> bool {
+        self.status.is_some_and(|s| (200..300).contains(&s))
+    }
+
+    fn describe(&self) -> String {
+        match (self.status, &self.error) {
+            (Some(status), _) => format!("http status {status}"),
+            (None, Some(error)) => error.clone(),
+            (None, None) => "no response".to_string(),
+        }
+    }
+}
+
+/// results of previous checks, by url
+type Cache = BTreeMap<String, Checked>;
+
+/// an external link, and where it is
+struct Found {
+    page: PathBuf,
+    line: usize,
+    column: usize,
+    url: Url,
+}
+
+/// the part of a url that matters to the server, used as the cache key
+fn cache_key(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url.into()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// every http(s) link in the repository
+fn find_urls(repo: &Repository, commit: &Commit) -> Vec<Found> {
+    let mut found = vec![];
+
+    map_org(repo, commit, |name, blob| {
+        let Ok(fstr) = std::str::from_utf8(blob.content()) else {
+            return;
+        };
+        let res = Org::parse(fstr);
+        org_link_urls(&res, &file_root(&name), |url, link| {
+            if !matches!(url.scheme(), "http" | "https") {
+                return;
+            }
+            let (line, column) = line_col(fstr, link.syntax().text_range().start().into());
+            found.push(Found {
+                page: name.clone(),
+                line,
+                column,
+                url,
+            });
+        });
+    })
+    .unwrap();
+
+    found
+}
+
+fn read_cache(path: &Path) -> Cache {
+    let Ok(file) = File::open(path) else {
+        return Cache::new();
+    };
+    serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+        eprintln!("ignoring broken cache {}: {e}", path.display());
+        Cache::new()
+    })
+}
+
+fn write_cache(path: &Path, cache: &Cache) {
+    let res = File::create(path)
+        .map_err(serde_json::Error::io)
+        .and_then(|f| serde_json::to_writer_pretty(BufWriter::new(f), cache));
+    if let Err(e) = res {
+        eprintln!("could not write cache {}: {e}", path.display());
+    }
+}
+
+/// spaces out requests to the same host
+struct HostLimiter {
+    delay: Duration,
+    /// when each host may be sent the next request
+    next: Mutex<HashMap<String, Instant>>,
+}
+
+impl HostLimiter {
+    fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            next: Mutex::default(),
+        }
+    }
+
+    /// block until it is this host's turn again
+    fn wait(&self, host: &str) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let at = next.get(host).map_or(now, |&at| at.max(now));
+            next.insert(host.to_string(), at + self.delay);
+            at - now
+        };
+        thread::sleep(wait);
+    }
+}
+
+fn make_agent() -> Agent {
+    Agent::config_builder()
+        .http_status_as_error(false)
+        .timeout_global(Some(Duration::from_secs(30)))
+        .user_agent(concat!("clam/", env!("CARGO_PKG_VERSION"), " linkcheck"))
+        .build()
+        .into()
+}
+
+/// status code of a url, falling back to GET for servers that do not like HEAD
+fn request(agent: &Agent, url: &str) -> Result<u16, ureq::Error> {
+    let status = agent.head(url).call()?.status().as_u16();
+    if matches!(status, 405 | 501) {
+        return Ok(agent.get(url).call()?.status().as_u16());
+    }
+    Ok(status)
+}
+
+/// check a url, retrying with increasing delays if it looks like the failure might go away
+fn check_url(agent: &Agent, limiter: &HostLimiter, url: &str, retries: u32) -> Checked {
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_default();
+
+    let mut attempt = 0;
+    loop {
+        limiter.wait(&host);
+        let res = request(agent, url);
+        let transient = match &res {
+            Ok(status) => *status == 429 || *status >= 500,
+            Err(_) => true,
+        };
+        if !transient || attempt >= retries {
+            let (status, error) = match res {
+                Ok(status) => (Some(status), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            return Checked {
+                status,
+                error,
+                time: unix_now(),
+            };
+        }
+        attempt += 1;
+        thread::sleep(limiter.delay * (1 << attempt.min(8)));
+    }
+}
+
+/// check a bunch of urls, with at most `jobs` requests going at once
+fn check_all(
+    urls: Vec<String>,
+    jobs: usize,
+    limiter: &HostLimiter,
+    retries: u32,
+) -> Vec<(String, Checked)> {
+    let agent = make_agent();
+    let queue = Mutex::new(urls);
+    let results = Mutex::new(vec![]);
+
+    thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            s.spawn(|| {
+                loop {
+                    let Some(url) = queue.lock().unwrap().pop() else {
+                        break;
+                    };
+                    let checked = check_url(&agent, limiter, &url, retries);
+                    results.lock().unwrap().push((url, checked));
+                }
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+pub fn print_linkcheck(repo: &Repository, commit: &Commit, args: &LinkcheckArgs) {
+    let allow = RegexSet::new(&args.allow).unwrap_or_else(|e| {
+        eprintln!("bad allow pattern: {e}");
+        std::process::exit(2);
+    });
+    let found: Vec<_> = find_urls(repo, commit)
+        .into_iter()
+        .filter(|f| !allow.is_match(f.url.as_str()))
+        .collect();
+
+    let mut cache = args.cache.as_deref().map(read_cache).unwrap_or_default();
+
+    if !args.offline {
+        let now = unix_now();
+        // broken links are always checked again, in case they got better
+        let stale: BTreeSet<_> = found
+            .iter()
+            .map(|f| cache_key(&f.url))
+            .filter(|k| {
+                cache
+                    .get(k)
+                    .is_none_or(|c| !c.ok() || now.saturating_sub(c.time) > args.max_age)
+            })
+            .collect();
+        let limiter = HostLimiter::new(Duration::from_millis(args.host_delay));
+        cache.extend(check_all(
+            stale.into_iter().collect(),
+            args.jobs,
+            &limiter,
+            args.retries,
+        ));
+        if let Some(path) = &args.cache {
+            write_cache(path, &cache);
+        }
+    }
+
+    let mut unchecked = 0;
+    let problems = found
+        .into_iter()
+        .filter_map(|f| {
+            let Some(checked) = cache.get(&cache_key(&f.url)) else {
+                unchecked += 1;
+                return None;
+            };
+            (!checked.ok()).then(|| Problem {
+                message: format!("{}: {}", f.url, checked.describe()),
+                page: f.page,
+                line: f.line,
+                column: f.column,
+            })
+        })
+        .collect();
+    if unchecked > 0 {
+        eprintln!("{unchecked} links were not in the cache, so they were not checked");
+    }
+
+    report(
+        problems,
+        &Rule {
+            id: "linkcheck",
+            description: "link to an external page that could not be reached",
+        },
+        &args.check,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::linkcheck::*;
+    use std::{
+        io::{BufRead, Write},
+        net::TcpListener,
+    };
+
+    /// a tiny local http server, answering requests with these statuses in order
+    fn stand_in(statuses: &'static [u16]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                _ = write!(
+                    stream,
+                    "HTTP/1.1 {status} meow\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+            }
+        });
+        format!("http://{addr}/")
+    }
+
+    #[test]
+    fn check_against_stand_in() {
+        let agent = make_agent();
+        let limiter = HostLimiter::new(Duration::from_millis(1));
+
+        let checked = check_url(&agent, &limiter, &stand_in(&[200]), 0);
+        assert!(checked.ok());
+
+        let checked = check_url(&agent, &limiter, &stand_in(&[404, 200]), 3);
+        assert_eq!(checked.status, Some(404));
+
+        let checked = check_url(&agent, &limiter, &stand_in(&[503, 429, 204]), 3);
+        assert_eq!(checked.status, Some(204));
+
+        let checked = check_url(&agent, &limiter, &stand_in(&[405, 200]), 0);
+        assert_eq!(checked.status, Some(200));
+
+        let checked = check_url(&agent, &limiter, &stand_in(&[500, 500]), 1);
+        assert_eq!(checked.describe(), "http status 500");
+    }
+}
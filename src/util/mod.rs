@@ -4,10 +4,13 @@ use orgize::Org;
 use std::path::{Path, PathBuf};
 
 pub mod dot;
+pub mod epub;
 pub mod jsonindex;
+pub mod linkcheck;
 pub mod orphan;
 pub mod preview;
 pub mod redlink;
+pub mod report;
 pub mod single;
 
 /// run a function on every link in an org document
@@ -58,3 +61,26 @@ where
         0
     })
 }
+
+/// which line and column some byte offset of a text is at, counting from 1
+pub fn line_col(text: &str, offset: usize) -> (usize, usize) {
+    let before = text.get(..offset).unwrap_or(text);
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// guess the mime type of an image from its file extension
+pub fn image_mime(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some(match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        _ => return None,
+    })
+}
@@ -1,26 +1,110 @@
-use super::{find_links, map_org};
+use super::{
+    find_links, map_org,
+    report::{Problem, Rule, report},
+};
+use crate::{
+    CheckArgs,
+    config::{ClamConfig, OverrideConfig, read_config},
+    helpers::{file_root, repo_path},
+};
 use git2::{Commit, Repository};
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
 
-fn get_orphans(repo: &Repository, commit: &Commit) -> HashSet<PathBuf> {
-    let mut pages = HashSet::new();
-    let mut links = HashSet::new();
+/// pages readers can get to without following links: the root index, configured entry points,
+/// redirect targets and pages in feeds
+fn entry_points<'a>(
+    config: Option<&ClamConfig>,
+    pages: impl Iterator<Item = &'a PathBuf>,
+    args: &CheckArgs,
+) -> Vec<PathBuf> {
+    let mut roots = vec![PathBuf::from("index.org")];
+    let Some(config) = config else {
+        return roots;
+    };
+
+    roots.extend(config.entry_points.iter().cloned());
+    for redirect in &config.redirect {
+        if let Ok(url) = file_root(&redirect.path).join(&redirect.target)
+            && let Some(target) = repo_path(&url)
+        {
+            roots.push(target);
+        }
+    }
+    if !config.feed.is_empty() {
+        let ext = args.repo.format.to_ext();
+        roots.extend(
+            pages
+                .filter(|p| {
+                    p.with_extension(ext)
+                        .to_str()
+                        .is_some_and(|out| config.feed.iter().any(|f| f.contains(out)))
+                })
+                .cloned(),
+        );
+    }
+
+    roots
+}
+
+fn get_orphans(repo: &Repository, commit: &Commit, args: &CheckArgs) -> Vec<PathBuf> {
+    let mut links: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
 
     map_org(repo, commit, |name, blob| {
+        let mut targets = vec![];
         find_links(&name, &blob, |l| {
-            links.insert(l.to_owned());
+            targets.push(l.to_owned());
         });
-        pages.insert(name);
+        links.insert(name, targets);
     })
     .unwrap();
+    let config = read_config(&OverrideConfig {
+        url: args.repo.url.clone(),
+        inline: args.repo.inline,
+        format: args.repo.format,
+    });
+
+    // pages linked from pages that nobody can get to do not count
+    let mut reachable = HashSet::new();
+    let mut queue = entry_points(config.as_ref(), links.keys(), args);
+    while let Some(page) = queue.pop() {
+        if let Some(targets) = links.get(&page)
+            && reachable.insert(page)
+        {
+            queue.extend(targets.iter().cloned());
+        }
+    }
 
-    pages.difference(&links).cloned().collect()
+    links
+        .into_keys()
+        .filter(|p| !reachable.contains(p))
+        .filter(|p| {
+            !config
+                .as_ref()
+                .is_some_and(|c| p.to_str().is_some_and(|p| c.orphan_allow.is_match(p)))
+        })
+        .collect()
 }
 
-pub fn print_orphans(repo: &Repository, commit: &Commit) {
-    let orphans = get_orphans(repo, commit);
+pub fn print_orphans(repo: &Repository, commit: &Commit, args: &CheckArgs) {
+    let problems = get_orphans(repo, commit, args)
+        .into_iter()
+        .map(|page| Problem {
+            page,
+            line: 1,
+            column: 1,
+            message: "no way to get here from the entry points".to_string(),
+        })
+        .collect();
 
-    for o in orphans {
-        println!("{}", o.display());
-    }
+    report(
+        problems,
+        &Rule {
+            id: "orphan",
+            description: "page that cannot be reached by following links from the entry points",
+        },
+        args,
+    );
 }
@@ -11,10 +11,13 @@ use std::{
 #[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
 
-use crate::output::{
-    accumulate, get_keywords,
-    html::{Handler, PageHtml},
-    infer_title,
+use crate::{
+    macros::{MacroMap, expand_macros},
+    output::{
+        accumulate, get_keywords,
+        html::{Handler, PageHtml},
+        infer_title,
+    },
 };
 
 pub fn serve(org_cfg: &ParseConfig, bindhost: SocketAddr) {
@@ -125,7 +128,9 @@ fn preview_static(pathb: &PathBuf) -> Result<(File, u64)> {
 
 fn preview_page(path: &Path, org_cfg: &ParseConfig) -> Option<String> {
     let fstr = read_to_string(path).ok()?;
-    let res = org_cfg.clone().parse(fstr);
+    // the config might not be there or be out of date, so only per-file macros here
+    let fstr = expand_macros(path, fstr.as_bytes(), &MacroMap::new(), org_cfg);
+    let res = org_cfg.clone().parse(str::from_utf8(&fstr).ok()?);
 
     let title = res.title().unwrap_or_else(|| infer_title(path));
     let keywords = get_keywords(&res);
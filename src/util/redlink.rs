@@ -1,28 +1,150 @@
-use super::{find_links, map_org};
+use super::{
+    line_col, map_org,
+    report::{Problem, Rule, report},
+};
+use crate::{
+    CheckArgs,
+    helpers::{file_root, org_link_urls, repo_path},
+    output::html::generate_headline_id,
+};
 use git2::{Commit, Repository};
-use std::{collections::HashSet, path::PathBuf};
+use orgize::{Org, ast::Headline, export::HtmlEscape, rowan::ast::AstNode};
+use percent_encoding::percent_decode_str;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
 
-fn get_redlinks(repo: &Repository, commit: &Commit) -> HashSet<PathBuf> {
-    let mut pages = HashSet::new();
-    let mut links = HashSet::new();
+/// a link to a page or headline that does not exist
+struct Redlink {
+    /// page the link is on
+    page: PathBuf,
+    line: usize,
+    column: usize,
+    target: PathBuf,
+    fragment: Option<String>,
+    /// whether only the headline is missing, not the whole page
+    target_exists: bool,
+}
+
+/// the ids headlines in a document get, which fragments can point at
+fn headline_ids(res: &Org) -> HashSet<String> {
+    res.document()
+        .syntax()
+        .descendants()
+        .filter_map(Headline::cast)
+        .map(|h| generate_headline_id(&h))
+        .collect()
+}
 
-    map_org(repo, commit, |name, blob| {
-        find_links(&name, &blob, |l| {
-            if l.extension().is_some_and(|e| e.eq_ignore_ascii_case("org")) {
-                links.insert(l.to_owned());
+/// every page, along with the headline ids in it, and every link to a page
+#[derive(Default)]
+struct Pages {
+    ids: HashMap<PathBuf, HashSet<String>>,
+    links: Vec<Redlink>,
+}
+
+impl Pages {
+    fn add(&mut self, name: PathBuf, content: &[u8]) {
+        let Ok(fstr) = std::str::from_utf8(content) else {
+            self.ids.insert(name, HashSet::new());
+            return;
+        };
+        let res = Org::parse(fstr);
+        org_link_urls(&res, &file_root(&name), |url, link| {
+            let Some(target) = repo_path(&url) else {
+                return;
+            };
+            if !target
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("org"))
+            {
+                return;
             }
+            let fragment = url
+                .fragment()
+                .filter(|f| !f.is_empty())
+                .map(|f| percent_decode_str(f).decode_utf8_lossy().into_owned());
+            let (line, column) = line_col(fstr, link.syntax().text_range().start().into());
+            self.links.push(Redlink {
+                page: name.clone(),
+                line,
+                column,
+                target,
+                fragment,
+                target_exists: false,
+            });
         });
-        pages.insert(name);
-    })
-    .unwrap();
+        self.ids.insert(name, headline_ids(&res));
+    }
+
+    fn redlinks(self) -> Vec<Redlink> {
+        let ids = self.ids;
+        self.links
+            .into_iter()
+            .filter_map(|mut l| {
+                let Some(ids) = ids.get(&l.target) else {
+                    return Some(l);
+                };
+                l.target_exists = true;
+                l.fragment
+                    .as_ref()
+                    .is_some_and(|f| !ids.contains(&HtmlEscape(f).to_string()))
+                    .then_some(l)
+            })
+            .collect()
+    }
+}
+
+fn get_redlinks(repo: &Repository, commit: &Commit) -> Vec<Redlink> {
+    let mut pages = Pages::default();
+    map_org(repo, commit, |name, blob| pages.add(name, blob.content())).unwrap();
+    pages.redlinks()
+}
+
+pub fn print_redlinks(repo: &Repository, commit: &Commit, args: &CheckArgs) {
+    let problems = get_redlinks(repo, commit)
+        .into_iter()
+        .map(|l| Problem {
+            message: match l.fragment {
+                Some(fragment) if l.target_exists => {
+                    format!("link to missing headline {}#{fragment}", l.target.display())
+                }
+                _ => format!("link to missing page {}", l.target.display()),
+            },
+            page: l.page,
+            line: l.line,
+            column: l.column,
+        })
+        .collect();
 
-    links.difference(&pages).cloned().collect()
+    report(
+        problems,
+        &Rule {
+            id: "redlink",
+            description: "link to a page or headline that does not exist",
+        },
+        args,
+    );
 }
 
-pub fn print_redlinks(repo: &Repository, commit: &Commit) {
-    let redlinks = get_redlinks(repo, commit);
+#[cfg(test)]
+mod tests {
+    use crate::util::redlink::*;
 
-    for l in redlinks {
-        println!("{}", l.display());
+    #[test]
+    fn check_fragments() {
+        let mut pages = Pages::default();
+        pages.add("a.org".into(), b"* Some Headline\nmeow\n");
+        pages.add(
+            "b.org".into(),
+            b"[[file:a.org#some-headline][good]]\n[[a.org#nope][bad]]\n[[file:missing.org#x][missing]]\n",
+        );
+        let redlinks = pages.redlinks();
+        let found: Vec<_> = redlinks
+            .iter()
+            .map(|l| (l.line, l.fragment.as_deref(), l.target_exists))
+            .collect();
+        assert_eq!(found, [(2, Some("nope"), true), (3, Some("x"), false)]);
     }
 }
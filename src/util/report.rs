@@ -0,0 +1,89 @@
+use crate::{CheckArgs, helpers::URL_PATH_UNSAFE};
+use percent_encoding::utf8_percent_encode;
+use serde::Serialize;
+use serde_json::json;
+use std::path::PathBuf;
+
+/// something wrong with a page, and where it is
+#[derive(Serialize)]
+pub struct Problem {
+    pub page: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// what kind of problems a check looks for, for tools that want to know
+pub struct Rule {
+    pub id: &'static str,
+    pub description: &'static str,
+}
+
+/// print problems sorted by where they are, in the format asked for
+///
+/// exits with an error if there were any
+pub fn report(mut problems: Vec<Problem>, rule: &Rule, args: &CheckArgs) {
+    problems.sort_by(|a, b| {
+        (&a.page, a.line, a.column, &a.message).cmp(&(&b.page, b.line, b.column, &b.message))
+    });
+
+    if args.sarif {
+        print_sarif(&problems, rule);
+    } else if args.json {
+        for problem in &problems {
+            println!("{}", serde_json::to_string(problem).unwrap());
+        }
+    } else {
+        for problem in &problems {
+            println!(
+                "{}:{}:{}: {}",
+                problem.page.display(),
+                problem.line,
+                problem.column,
+                problem.message
+            );
+        }
+    }
+
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn print_sarif(problems: &[Problem], rule: &Rule) {
+    let results: Vec<_> = problems
+        .iter()
+        .map(|p| {
+            let uri = utf8_percent_encode(&p.page.to_string_lossy(), URL_PATH_UNSAFE).to_string();
+            json!({
+                "ruleId": rule.id,
+                "level": "error",
+                "message": { "text": p.message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": uri },
+                        "region": { "startLine": p.line, "startColumn": p.column },
+                    },
+                }],
+            })
+        })
+        .collect();
+    let log = json!({
+        "version": "2.1.0",
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": [{
+                        "id": rule.id,
+                        "shortDescription": { "text": rule.description },
+                    }],
+                },
+            },
+            "results": results,
+        }],
+    });
+    println!("{log}");
+}
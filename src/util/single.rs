@@ -1,19 +1,31 @@
 use crate::{
-    Error, STYLESHEET_STR,
+    Error, OutputFormat, STYLESHEET_STR, SingleArgs,
+    config::{OverrideConfig, read_config},
     git::HistMeta,
-    helpers::org_links,
-    output::{Page, PageKeywords, PageMetadata, accumulate, get_keywords, infer_title},
+    helpers::{URL_PATH_UNSAFE, org_links, repo_path},
+    include::{IncludeMap, apply_includes, expand_includes},
+    macros::{MacroMap, expand_macros, site_macros},
+    output::{
+        Page, PageKeywords, PageMetadata, SiteIndex, accumulate, get_keywords,
+        gmi::GmiExport,
+        html::{Handler, generate_headline_id},
+        infer_title, site_index, stale_notice, year_ago,
+    },
 };
 use boilerplate::Trusted;
 use chrono::{DateTime, Datelike};
-use git2::{Commit, Repository};
+use git2::{Commit, Repository, Tree};
 use orgize::{
-    ParseConfig,
+    ParseConfig, SyntaxKind,
+    ast::Headline,
     export::{Container, Event, HtmlEscape, Traverser},
+    rowan::NodeOrToken,
 };
+use percent_encoding::utf8_percent_encode;
 use slugify::slugify;
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
     path::{Path, PathBuf},
     rc::Rc,
 };
@@ -21,34 +33,181 @@ use url::Url;
 
 #[derive(boilerplate::Boilerplate)]
 struct SingleHtml<'a> {
+    header: Option<&'a str>,
     entries: &'a [Entry<'a>],
 }
 
-struct Entry<'a> {
-    title: &'a str,
-    slug: &'a str,
-    body: &'a str,
-    metadata: PageMetadata<'a>,
+#[derive(boilerplate::Boilerplate)]
+struct SingleGmi<'a> {
+    entries: &'a [Entry<'a>],
+    /// where each page lives on the built site, by slug
+    canonical: HashMap<&'a str, &'a str>,
+}
+
+pub struct Entry<'a> {
+    pub title: &'a str,
+    pub slug: &'a str,
+    pub body: &'a str,
+    pub notice: Option<&'static str>,
+    /// where this page lives on the built site
+    pub canonical: Option<String>,
+    pub metadata: PageMetadata<'a>,
+}
+
+/// what to do with links to images inside the repository
+pub enum LocalImages<'a> {
+    /// leave them pointing at their original location
+    Keep,
+    /// collect their paths, so they can be bundled alongside the pages
+    Collect(&'a mut BTreeSet<PathBuf>),
+    /// embed them as data uris, unless they are too large
+    Inline {
+        repo: &'a Repository,
+        tree: &'a Tree<'a>,
+        max_size: usize,
+    },
+}
+
+impl LocalImages<'_> {
+    /// figure out the new `src` of a local image, if it should change
+    fn src(&mut self, file: &Path, url: &Url, mime: &str) -> Option<String> {
+        match self {
+            Self::Keep => None,
+            Self::Collect(images) => {
+                images.insert(file.to_owned());
+                Some(url.path()[1..].to_string())
+            }
+            Self::Inline {
+                repo,
+                tree,
+                max_size,
+            } => {
+                let Some(blob) = tree
+                    .get_path(file)
+                    .and_then(|e| e.to_object(repo))
+                    .ok()
+                    .and_then(|o| o.into_blob().ok())
+                else {
+                    eprintln!("missing image {}", file.display());
+                    return None;
+                };
+                if blob.size() > *max_size {
+                    eprintln!(
+                        "not embedding {}, it is over {max_size} bytes",
+                        file.display()
+                    );
+                    return None;
+                }
+                Some(format!("data:{mime};base64,{}", Base64(blob.content())))
+            }
+        }
+    }
 }
 
-struct LinkSlugExport {
-    myurl: Url,
-    exp: crate::output::html::Handler,
+/// `Display`s bytes as padded base64, for use in data uris
+struct Base64<'a>(&'a [u8]);
+
+impl fmt::Display for Base64<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use std::fmt::Write;
+
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+        for chunk in self.0.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    f.write_char(ALPHABET[(n >> (18 - 6 * i) & 63) as usize].into())?;
+                } else {
+                    f.write_char('=')?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// a headline, as shown in a table of contents
+pub struct TocEntry {
+    pub level: usize,
+    pub id: String,
+    pub title: String,
 }
 
-impl Traverser for LinkSlugExport {
+/// how pages get bundled together
+pub struct Bundle<'a> {
+    /// format of page bodies
+    pub format: OutputFormat,
+    /// extension of the separate xhtml document each page gets, or `None` if every page
+    /// shares a single html document
+    pub ext: Option<&'a str>,
+    /// url and page extension of the built site, for gemtext to link to, as it has no anchors
+    pub canonical: Option<(&'a str, &'a str)>,
+    pub images: LocalImages<'a>,
+    /// headlines of every page, by slug
+    pub toc: HashMap<String, Vec<TocEntry>>,
+    pub site: Rc<SiteIndex>,
+}
+
+impl Default for Bundle<'_> {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::Html,
+            ext: None,
+            canonical: None,
+            images: LocalImages::Keep,
+            toc: HashMap::new(),
+            site: Rc::default(),
+        }
+    }
+}
+
+pub struct LinkSlugExport<'a, 'b> {
+    pub myurl: Url,
+    pub exp: Handler,
+    pub bundle: &'a mut Bundle<'b>,
+    pub headlines: Vec<TocEntry>,
+}
+
+impl Traverser for LinkSlugExport<'_, '_> {
     fn event(&mut self, event: orgize::export::Event, ctx: &mut orgize::export::TraversalContext) {
         match event {
+            Event::Enter(Container::Headline(ref headline)) => {
+                self.headlines.push(TocEntry {
+                    level: headline.level(),
+                    id: generate_headline_id(headline),
+                    title: headline_text(headline),
+                });
+                self.exp.event(event, ctx);
+            }
             Event::Enter(Container::Link(ref link)) => {
                 let path = link.path();
 
-                // FIXME: would be nice to turn local images into data uris
-                if path.starts_with("abbr:") || link.is_image() {
+                if path.starts_with("abbr:") {
+                    self.exp.event(event, ctx);
+                    return;
+                }
+
+                if link.is_image() {
+                    if let Ok(url) = self.myurl.join(&path)
+                        && url.scheme() == "file"
+                        && let Ok(file) = url.to_file_path()
+                        && let Ok(file) = file.strip_prefix("/")
+                        && let Some(mime) = super::image_mime(file)
+                        && let Some(src) = self.bundle.images.src(file, &url, mime)
+                    {
+                        self.exp.output_image(link, &src);
+                        return ctx.skip();
+                    }
                     self.exp.event(event, ctx);
                     return;
                 }
 
-                let path = slug_url(path, &self.myurl);
+                let path = slug_url(path, &self.myurl, self.bundle.ext);
 
                 self.exp
                     .exp
@@ -59,12 +218,40 @@ impl Traverser for LinkSlugExport {
                     ctx.skip();
                 }
             }
+            // xhtml only knows about a handful of named entities
+            Event::Entity(entity) if self.bundle.ext.is_some() => {
+                self.exp.exp.push_str(HtmlEscape(entity.utf8()).to_string());
+            }
             _ => self.exp.event(event, ctx),
         }
     }
 }
 
-fn slug_url(url: impl AsRef<str>, current: &Url) -> String {
+/// plain text of a headline's title, without any markup
+fn headline_text(headline: &Headline) -> String {
+    let mut text = String::new();
+    for e in headline.title() {
+        match e {
+            NodeOrToken::Node(n) => {
+                for t in n.descendants_with_tokens() {
+                    if let NodeOrToken::Token(t) = t
+                        && t.kind() == SyntaxKind::TEXT
+                    {
+                        text.push_str(t.text());
+                    }
+                }
+            }
+            NodeOrToken::Token(t) => {
+                if t.kind() == SyntaxKind::TEXT {
+                    text.push_str(t.text());
+                }
+            }
+        }
+    }
+    text.trim().to_string()
+}
+
+pub fn slug_url(url: impl AsRef<str>, current: &Url, ext: Option<&str>) -> String {
     let url = url.as_ref();
     if let Some(f) = url.strip_prefix('*') {
         return format!("#{}", slugify!(f));
@@ -72,28 +259,30 @@ fn slug_url(url: impl AsRef<str>, current: &Url) -> String {
     if let Ok(url) = current.join(url)
         && url.scheme() == "file"
     {
-        return if let Some(f) = url.fragment() {
-            format!("#{f}")
+        let slug = slugify!(url.path());
+        let mindex = if url.path().ends_with('/') {
+            "-index-org"
         } else {
-            let slug = slugify!(url.path());
-            let mindex = if url.path().ends_with('/') {
-                "-index-org"
-            } else {
-                ""
-            };
-            format!("#{slug}{mindex}")
+            ""
+        };
+        return match (ext, url.fragment()) {
+            (None, Some(f)) => format!("#{f}"),
+            (None, None) => format!("#{slug}{mindex}"),
+            (Some(ext), Some(f)) => format!("{slug}{mindex}.{ext}#{f}"),
+            (Some(ext), None) => format!("{slug}{mindex}.{ext}"),
         };
     }
     url.to_string()
 }
 
-fn generate_page(
+pub fn generate_page(
     dir: &str,
     name: &str,
     file: &[u8],
     org_cfg: &ParseConfig,
     pages: &mut BTreeMap<String, Page>,
     links: &mut HashMap<PathBuf, Vec<Rc<String>>>,
+    bundle: &mut Bundle,
 ) -> Result<(), Error> {
     let full_path = format!("{dir}{name}");
     let old_path = PathBuf::from(&full_path);
@@ -119,15 +308,61 @@ fn generate_page(
             }
         });
 
-        let mut html_export = LinkSlugExport {
-            myurl: Url::from_file_path(&bpath).unwrap(),
-            exp: crate::output::html::Handler {
-                accumulated,
-                ..Default::default()
-            },
+        let myurl = Url::from_file_path(&bpath).unwrap();
+        let body = match bundle.format {
+            OutputFormat::Html => {
+                let ext = bundle.ext.map(str::to_string);
+                let relink_url = myurl.clone();
+                let mut html_export = LinkSlugExport {
+                    myurl,
+                    exp: Handler {
+                        accumulated,
+                        relink: Some(Box::new(move |path| {
+                            slug_url(path, &relink_url, ext.as_deref())
+                        })),
+                        site: Some(bundle.site.clone()),
+                        path: old_path.clone(),
+                        ..Default::default()
+                    },
+                    bundle,
+                    headlines: vec![],
+                };
+                res.traverse(&mut html_export);
+                let LinkSlugExport { exp, headlines, .. } = html_export;
+                bundle.toc.insert(slugify!(&full_path), headlines);
+                exp.exp.finish()
+            }
+            OutputFormat::Gmi => {
+                let mut gmi_export = GmiExport::default();
+                gmi_export.accumulated = accumulated;
+                gmi_export.site = Some(bundle.site.clone());
+                gmi_export.path.clone_from(&old_path);
+                let canonical = bundle
+                    .canonical
+                    .map(|(url, ext)| (url.to_string(), ext.to_string()));
+                gmi_export.relink = Some(Box::new(move |path| {
+                    // there is nothing to point at for images
+                    if super::image_mime(Path::new(path)).is_some() {
+                        return Some(path.to_string());
+                    }
+                    let target = if path.starts_with('*') {
+                        myurl.clone()
+                    } else {
+                        // the site has no way to search within a page either
+                        let file = path.split_once("::").map_or(path, |(file, _)| file);
+                        match myurl.join(file) {
+                            Ok(url) if url.scheme() == "file" => url,
+                            _ => return Some(path.to_string()),
+                        }
+                    };
+                    // pages only get anchors on the built site
+                    let (url, ext) = canonical.as_ref()?;
+                    canonical_url(url, &repo_path(&target)?, ext)
+                }));
+                res.traverse(&mut gmi_export);
+                gmi_export.finish()
+            }
         };
-        res.traverse(&mut html_export);
-        let body = html_export.exp.exp.finish();
 
         Page {
             title,
@@ -140,7 +375,10 @@ fn generate_page(
             return Ok(());
         };
         let title = infer_title(&bpath);
-        let body = format!("<pre>{}</pre>", HtmlEscape(&fstr));
+        let body = match bundle.format {
+            OutputFormat::Html => format!("<pre>{}</pre>", HtmlEscape(&fstr)),
+            OutputFormat::Gmi => format!("```\n{}\n```\n\n", fstr.trim_end_matches('\n')),
+        };
         Page {
             title,
             old_path,
@@ -157,11 +395,58 @@ fn generate_page(
     Ok(())
 }
 
-fn generate_entries<'a>(
+/// generate every page in the tree, giving back what each of them included
+pub fn generate_pages(
+    repo: &Repository,
+    tree: &Tree,
+    org_cfg: &ParseConfig,
+    pages: &mut BTreeMap<String, Page>,
+    links: &mut HashMap<PathBuf, Vec<Rc<String>>>,
+    bundle: &mut Bundle,
+    site_macros: &MacroMap,
+) -> IncludeMap {
+    let mut includes = IncludeMap::new();
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        if let Err(e) = crate::git::walk_callback(repo, dir, entry, false, |name, blob| {
+            let path = PathBuf::from(format!("{dir}{name}"));
+            let file = expand_includes(repo, tree, org_cfg, &path, blob.content(), &mut includes);
+            let file = expand_macros(&path, &file, site_macros, org_cfg);
+            generate_page(dir, name, &file, org_cfg, pages, links, bundle)
+        }) {
+            eprintln!("{e}");
+        }
+        0
+    })
+    .unwrap();
+    includes
+}
+
+/// where the file at `path` lives on the site built at `url`, with org pages ending in `ext`
+fn canonical_url(url: &str, path: &Path, ext: &str) -> Option<String> {
+    let mut path = path.to_path_buf();
+    if path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("org"))
+    {
+        path.set_extension(ext);
+    }
+    Some(format!(
+        "{url}/{}",
+        utf8_percent_encode(path.to_str()?, URL_PATH_UNSAFE)
+    ))
+}
+
+/// build entries for each page, with `url` and `ext` used to link back to the built site
+pub fn generate_entries<'a>(
     pages: &'a BTreeMap<String, Page>,
     links: &'a HashMap<PathBuf, Vec<Rc<String>>>,
     hist: &'a HashMap<PathBuf, HistMeta>,
+    footer: Option<&'a str>,
+    url: Option<&str>,
+    ext: &str,
 ) -> Vec<Entry<'a>> {
+    let year_ago = year_ago().expect("system clock should be reasonable");
+
     pages
         .iter()
         .map(
@@ -208,6 +493,8 @@ fn generate_entries<'a>(
 
                 let contributors = contributors.len() - usize::from(contributors.contains(author));
 
+                let canonical = url.and_then(|url| canonical_url(url, old_path, ext));
+
                 let metadata = PageMetadata {
                     author,
                     commit: last_commit,
@@ -216,13 +503,15 @@ fn generate_entries<'a>(
                         .naive_utc(),
                     year,
                     incoming,
-                    footer: None,
+                    footer,
                     contributors,
                 };
                 Entry {
                     title,
                     slug,
                     body,
+                    notice: stale_notice(modify_time, year_ago),
+                    canonical,
                     metadata,
                 }
             },
@@ -230,24 +519,156 @@ fn generate_entries<'a>(
         .collect()
 }
 
-pub fn print_html(repo: &Repository, commit: &Commit) {
+/// strip gemtext down to plain text, turning link lines into inline urls
+fn gmi_to_text(gmi: &str) -> String {
+    let mut out = String::with_capacity(gmi.len());
+    let mut pre = false;
+
+    for line in gmi.lines() {
+        if line.starts_with("```") {
+            pre = !pre;
+            continue;
+        }
+        if pre {
+            out.push_str(line);
+        } else if let Some(link) = line.strip_prefix("=>") {
+            let link = link.trim_start();
+            if let Some((url, label)) = link.split_once(char::is_whitespace) {
+                out.push_str(label.trim());
+                out.push_str(" <");
+                out.push_str(url);
+                out.push('>');
+            } else {
+                out.push('<');
+                out.push_str(link);
+                out.push('>');
+            }
+        } else if line.starts_with('#') {
+            out.push_str(line.trim_start_matches('#').trim_start());
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+pub fn print_single(repo: &Repository, commit: &Commit, args: &SingleArgs) {
     let tree = commit.tree().unwrap();
-    let hmeta = crate::git::make_time_tree(repo, commit.id()).unwrap();
+    let mut hmeta = crate::git::make_time_tree(repo, commit.id()).unwrap();
     let org_cfg = crate::default_org_cfg();
+    let overrides = OverrideConfig {
+        url: args.repo.url.clone(),
+        inline: args.repo.inline,
+        format: args.repo.format,
+    };
+    let config = read_config(&overrides);
+    let buckets = config.as_ref().map_or(&[][..], |c| &c.site_buckets);
+    let site_macros = site_macros(repo, &tree, config.as_ref(), &org_cfg);
+    let site = Rc::new(site_index(repo, &tree, &org_cfg, buckets, &site_macros).unwrap());
+    let url = config
+        .as_ref()
+        .map(|c| c.url.as_str())
+        .or(args.repo.url.as_deref());
+    // --plain only renders through gemtext, canonical links still go to the site's pages
+    let ext = args.repo.format.to_ext();
     let mut pages = BTreeMap::new();
     let mut links = HashMap::new();
+    let mut bundle = if args.plain || matches!(args.repo.format, OutputFormat::Gmi) {
+        Bundle {
+            format: OutputFormat::Gmi,
+            canonical: url.map(|url| (url, ext)),
+            site,
+            ..Default::default()
+        }
+    } else {
+        Bundle {
+            images: LocalImages::Inline {
+                repo,
+                tree: &tree,
+                max_size: args.max_image_size,
+            },
+            site,
+            ..Default::default()
+        }
+    };
 
-    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
-        if let Err(e) = crate::git::walk_callback(repo, dir, entry, false, |name, blob| {
-            generate_page(dir, name, blob.content(), &org_cfg, &mut pages, &mut links)
-        }) {
-            eprintln!("{e}");
+    let includes = generate_pages(
+        repo,
+        &tree,
+        &org_cfg,
+        &mut pages,
+        &mut links,
+        &mut bundle,
+        &site_macros,
+    );
+    apply_includes(&mut hmeta, &includes);
+
+    let footer = config.as_ref().and_then(|c| c.extra_footer.as_deref());
+    let entries = generate_entries(&pages, &links, &hmeta, footer, url, ext);
+
+    if args.plain {
+        for entry in &entries {
+            println!("{}", entry.title);
+            println!("{}\n", "=".repeat(entry.title.chars().count()));
+            if let Some(notice) = entry.notice {
+                println!("{notice}\n");
+            }
+            println!("{}", gmi_to_text(entry.body).trim_end());
+            if let Some(canonical) = &entry.canonical {
+                println!("\nview the original page: {canonical}");
+            }
+            println!("\n");
         }
-        0
-    })
-    .unwrap();
+        return;
+    }
 
-    let entries = generate_entries(&pages, &links, &hmeta);
+    match bundle.format {
+        OutputFormat::Html => println!(
+            "{}",
+            SingleHtml {
+                header: config.as_ref().and_then(|c| c.extra_header.as_deref()),
+                entries: &entries,
+            }
+        ),
+        OutputFormat::Gmi => print!(
+            "{}",
+            SingleGmi {
+                entries: &entries,
+                canonical: entries
+                    .iter()
+                    .filter_map(|e| Some((e.slug, e.canonical.as_deref()?)))
+                    .collect(),
+            }
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::single::*;
 
-    println!("{}", SingleHtml { entries: &entries });
+    #[test]
+    fn check_base64() {
+        assert_eq!(Base64(b"").to_string(), "");
+        assert_eq!(Base64(b"f").to_string(), "Zg==");
+        assert_eq!(Base64(b"fo").to_string(), "Zm8=");
+        assert_eq!(Base64(b"foo").to_string(), "Zm9v");
+        assert_eq!(Base64(b"foob").to_string(), "Zm9vYg==");
+        assert_eq!(Base64(b"\xff\xfe\xfd").to_string(), "//79");
+    }
+
+    #[test]
+    fn check_canonical_url() {
+        let url = "https://wiki.example";
+        assert_eq!(
+            canonical_url(url, Path::new("sub/a page.org"), "gmi").unwrap(),
+            "https://wiki.example/sub/a%20page.gmi"
+        );
+        assert_eq!(
+            canonical_url(url, Path::new("notes.txt"), "html").unwrap(),
+            "https://wiki.example/notes.txt"
+        );
+    }
 }